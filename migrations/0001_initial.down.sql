@@ -0,0 +1,13 @@
+-- Drops every table, and all data with it
+
+DROP TABLE IF EXISTS login_challenges;
+DROP TABLE IF EXISTS recovery_codes;
+DROP TABLE IF EXISTS user_totp;
+DROP TABLE IF EXISTS password_resets;
+DROP TABLE IF EXISTS invitations;
+DROP TABLE IF EXISTS account_lockouts;
+DROP TRIGGER IF EXISTS revoke_sessions_on_credentials_change;
+DROP TABLE IF EXISTS sessions;
+DROP TABLE IF EXISTS loans;
+DROP TABLE IF EXISTS books;
+DROP TABLE IF EXISTS users;
@@ -0,0 +1,114 @@
+-- Baseline schema. Written with IF NOT EXISTS so databases created before
+-- migrations existed are adopted as they are; later migrations don't need to.
+
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT UNIQUE NOT NULL,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL,
+    email TEXT
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_users_email ON users(email);
+
+CREATE TABLE IF NOT EXISTS books (
+    bookid INTEGER PRIMARY KEY AUTOINCREMENT,
+    title TEXT NOT NULL,
+    author TEXT NOT NULL,
+    isbn TEXT UNIQUE NOT NULL,
+    year_of_pub INTEGER,
+    genre TEXT,
+    total_copies INTEGER NOT NULL DEFAULT 0,
+    available_copies INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS loans (
+    loanid INTEGER PRIMARY KEY AUTOINCREMENT,
+    loaned_to_user_id INTEGER NOT NULL,
+    loaned_bookid INTEGER NOT NULL,
+    checkout_date TEXT NOT NULL,
+    due_date TEXT NOT NULL,
+    return_date TEXT,
+    FOREIGN KEY(loaned_to_user_id) REFERENCES users(id),
+    FOREIGN KEY(loaned_bookid) REFERENCES books(bookid)
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash TEXT UNIQUE NOT NULL,
+    user_id INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    last_seen_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    remember INTEGER NOT NULL DEFAULT 0,
+    ip TEXT,
+    user_agent TEXT,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_sessions_user_id ON sessions(user_id);
+CREATE INDEX IF NOT EXISTS idx_sessions_expires_at ON sessions(expires_at);
+
+-- A new password or role signs the user out everywhere, whichever code
+-- path (or manual UPDATE) made the change
+CREATE TRIGGER IF NOT EXISTS revoke_sessions_on_credentials_change
+AFTER UPDATE OF password, role ON users
+WHEN OLD.password IS NOT NEW.password OR OLD.role IS NOT NEW.role
+BEGIN
+    DELETE FROM sessions WHERE user_id = NEW.id;
+END;
+
+-- Failed-login counters and temporary lockouts
+CREATE TABLE IF NOT EXISTS account_lockouts (
+    user_id INTEGER PRIMARY KEY,
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    locked_until TEXT,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Single-use staff invitations
+CREATE TABLE IF NOT EXISTS invitations (
+    token TEXT PRIMARY KEY,
+    role TEXT NOT NULL,
+    created_by INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    used_at TEXT,
+    FOREIGN KEY(created_by) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- One-time password reset tokens
+CREATE TABLE IF NOT EXISTS password_resets (
+    token TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    used_at TEXT,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- TOTP secrets; enabled_at stays NULL until the first code is confirmed
+CREATE TABLE IF NOT EXISTS user_totp (
+    user_id INTEGER PRIMARY KEY,
+    secret TEXT NOT NULL,
+    enabled_at TEXT,
+    last_used_step INTEGER,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Single-use 2FA recovery codes, bcrypt-hashed
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_at TEXT,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Logins that passed the password check and wait for a second factor
+CREATE TABLE IF NOT EXISTS login_challenges (
+    token_hash TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    remember INTEGER NOT NULL DEFAULT 0,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TEXT NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
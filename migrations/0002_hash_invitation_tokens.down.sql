@@ -0,0 +1,14 @@
+-- Back to plaintext tokens. Hashes can't be turned back into tokens, so
+-- pending invitations are discarded.
+
+DROP TABLE invitations;
+
+CREATE TABLE invitations (
+    token TEXT PRIMARY KEY,
+    role TEXT NOT NULL,
+    created_by INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    used_at TEXT,
+    FOREIGN KEY(created_by) REFERENCES users(id) ON DELETE CASCADE
+);
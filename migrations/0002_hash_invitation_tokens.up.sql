@@ -0,0 +1,17 @@
+-- Invitation tokens were stored as issued, so anyone who could read the
+-- database could sign up as staff. From now on only their SHA-256 is kept,
+-- and invitations are listed and revoked by id. SQLite can't hash the old
+-- tokens, so invitations still pending are discarded and must be issued again.
+
+DROP TABLE invitations;
+
+CREATE TABLE invitations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash TEXT UNIQUE NOT NULL,
+    role TEXT NOT NULL,
+    created_by INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    used_at TEXT,
+    FOREIGN KEY(created_by) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,471 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::auth::{
+    generate_session_token, hash_password, hash_token, normalize_email, verify_password, PasswordPolicy,
+};
+use crate::config::Config;
+use crate::error::{ApiError, ApiResult};
+use crate::http::{Conn, Request};
+use crate::mailer::{Email, Mailer};
+use crate::response::Response;
+use crate::router::{BoxFuture, Ctx, Router};
+use crate::session::{self, SessionStore};
+use crate::throttle::Throttle;
+use crate::{client_key, parse_form_urlencoded, send_json, send_redirect, send_response};
+
+pub fn routes(router: &mut Router) {
+    router
+        .post("/forgot-password", forgot_password)
+        .post("/reset-password", reset_password);
+
+    router
+        .logged_in()
+        .post("/account/api/password", change_password)
+        .put("/account/api/email", change_email)
+        .get("/account/api/sessions", sessions)
+        .delete("/account/api/sessions", revoke_other_sessions)
+        .delete("/account/api/sessions/{id}", revoke_session);
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordInput {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+struct ChangeEmailInput {
+    current_password: String,
+    email: String,
+}
+
+//----------------------------------------------------------------------------------------------------------
+// routes
+
+fn change_password(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        let (throttle, sessions, passwords) = (ctx.throttle, ctx.sessions, ctx.passwords);
+        handle_change_password(ctx.stream, ctx.pool, throttle, sessions, passwords, &username, ctx.request).await
+    })
+}
+
+fn change_email(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        let body = ctx.request.body_str();
+        handle_change_email(ctx.stream, ctx.pool, ctx.throttle, ctx.mailer, &username, &body).await
+    })
+}
+
+fn sessions(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        handle_sessions(ctx.stream, ctx.pool, ctx.sessions, &username, ctx.request).await
+    })
+}
+
+fn revoke_session(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        match ctx.params.get::<i64>("id") {
+            Some(id) => handle_revoke_session(ctx.stream, ctx.pool, &username, id).await,
+            None => Err(ApiError::Validation("id must be a number".to_string())),
+        }
+    })
+}
+
+fn revoke_other_sessions(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        let current = ctx.sessions.current_hash(ctx.request);
+        handle_revoke_other_sessions(ctx.stream, ctx.pool, &username, &current).await
+    })
+}
+
+fn forgot_password(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let body = ctx.request.body_str();
+        handle_forgot_password(ctx.stream, ctx.pool, ctx.config, ctx.throttle, ctx.mailer, &body).await
+    })
+}
+
+fn reset_password(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let body = ctx.request.body_str();
+        handle_reset_password(ctx.stream, ctx.pool, ctx.throttle, ctx.passwords, &body).await
+    })
+}
+
+//----------------------------------------------------------------------------------------------------------
+// account fns
+
+/// Re-checks the current password and stores the new one. Every session of
+/// the user ends; the caller gets a fresh one in the response.
+async fn handle_change_password(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    throttle: &Throttle,
+    sessions: &SessionStore,
+    passwords: &PasswordPolicy,
+    username: &str,
+    request: &Request,
+) -> ApiResult {
+    let input: ChangePasswordInput = serde_json::from_str(&request.body_str())
+        .map_err(|e| ApiError::Validation(format!("invalid password JSON: {}", e)))?;
+
+    // a stolen session must not be a way around the login backoff
+    let user_key = format!("user:{}", username);
+    if throttle.reserve(&[&user_key]).is_err() {
+        return Err(ApiError::TooManyRequests("too many attempts, try again later".to_string()));
+    }
+
+    let (user_id, db_password) = sqlx::query_as::<_, (i64, String)>(
+        "SELECT id, password FROM users WHERE username = ?",
+    )
+    .bind(username)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound("user not found".to_string()))?;
+
+    if !verify_password(&input.current_password, &db_password).await? {
+        return Err(ApiError::Forbidden("current password is incorrect".to_string()));
+    }
+    throttle.refund(&user_key);
+    passwords
+        .check(username, &input.new_password)
+        .map_err(ApiError::Validation)?;
+
+    let hashed = hash_password(&input.new_password).await?;
+    let remember = sessions.remembered(&pool, request).await;
+
+    // the users trigger deletes every session of the user, this one included
+    sqlx::query("UPDATE users SET password = ? WHERE id = ?")
+        .bind(&hashed)
+        .bind(user_id)
+        .execute(&pool)
+        .await?;
+    let issued = sessions
+        .create(&pool, user_id, &session::Origin::new(stream, request, remember))
+        .await?;
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": "Password changed, other sessions have been signed out"
+    });
+    let json = serde_json::to_vec(&response)?;
+    let [session_cookie, csrf_cookie] = issued.cookies(stream.is_secure());
+    Response::new(200)
+        .header("Content-Type", "application/json")
+        .cookie(session_cookie)
+        .cookie(csrf_cookie)
+        .body(&json)
+        .send(stream)
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Sets or replaces the address used for password resets. Whoever controls
+/// that address can reset the password, so the current password is checked
+/// as for a password change, and the old address is told about the change.
+async fn handle_change_email(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    throttle: &Throttle,
+    mailer: &Arc<dyn Mailer>,
+    username: &str,
+    body: &str,
+) -> ApiResult {
+    let input: ChangeEmailInput = serde_json::from_str(body)
+        .map_err(|e| ApiError::Validation(format!("invalid email JSON: {}", e)))?;
+    let email = normalize_email(&input.email)
+        .ok_or_else(|| ApiError::Validation("not a valid email address".to_string()))?;
+
+    let user_key = format!("user:{}", username);
+    if throttle.reserve(&[&user_key]).is_err() {
+        return Err(ApiError::TooManyRequests("too many attempts, try again later".to_string()));
+    }
+
+    let (user_id, db_password, old_email) = sqlx::query_as::<_, (i64, String, Option<String>)>(
+        "SELECT id, password, email FROM users WHERE username = ?",
+    )
+    .bind(username)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound("user not found".to_string()))?;
+
+    if !verify_password(&input.current_password, &db_password).await? {
+        return Err(ApiError::Forbidden("current password is incorrect".to_string()));
+    }
+    throttle.refund(&user_key);
+
+    // a duplicate address surfaces as a unique violation -> 409
+    sqlx::query("UPDATE users SET email = ? WHERE id = ?")
+        .bind(&email)
+        .bind(user_id)
+        .execute(&pool)
+        .await?;
+
+    if let Some(old_email) = old_email.filter(|old| *old != email) {
+        let message = Email {
+            to: old_email,
+            subject: "Your library email address was changed".to_string(),
+            body: format!(
+                "Hi {},\n\nThe email address for your library account was changed to {}.\n\
+                 Password reset links will go there from now on.\n\n\
+                 If you didn't make this change, ask a librarian to reset your account.\n",
+                username, email
+            ),
+        };
+        let mailer = mailer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mailer.send(&message).await {
+                tracing::error!(error = ?e, user_id, "Failed to send email change notice");
+            }
+        });
+    }
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": format!("Email set to {}", email)
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Lists the caller's live sessions, flagging the one making the request
+async fn handle_sessions(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    sessions: &SessionStore,
+    username: &str,
+    request: &Request,
+) -> ApiResult {
+    let user_id = sqlx::query_scalar::<_, i64>("SELECT id FROM users WHERE username = ?")
+        .bind(username)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("user not found".to_string()))?;
+
+    let list = sessions.list(&pool, user_id, request).await?;
+    let json = serde_json::to_vec(&list)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Signs out one of the caller's sessions; revoking the current one logs out
+async fn handle_revoke_session(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    username: &str,
+    id: i64,
+) -> ApiResult {
+    // scoped to the caller, so other users' session ids look like unknown ones
+    let result = sqlx::query(
+        "DELETE FROM sessions WHERE id = ? AND user_id = (SELECT id FROM users WHERE username = ?)",
+    )
+    .bind(id)
+    .bind(username)
+    .execute(&pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(format!("session {} not found", id)));
+    }
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": "Session signed out"
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Signs out every session of the caller except the current one
+async fn handle_revoke_other_sessions(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    username: &str,
+    current_hash: &str,
+) -> ApiResult {
+    let result = sqlx::query(
+        "DELETE FROM sessions WHERE user_id = (SELECT id FROM users WHERE username = ?) AND token_hash != ?",
+    )
+    .bind(username)
+    .bind(current_hash)
+    .execute(&pool)
+    .await?;
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": format!("Signed out {} other session(s)", result.rows_affected())
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Mails a reset link if the address belongs to an account. The reply is the
+/// same either way, and the mail goes out in the background so timing
+/// doesn't tell the two cases apart.
+async fn handle_forgot_password(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    config: &Config,
+    throttle: &Throttle,
+    mailer: &Arc<dyn Mailer>,
+    body: &str,
+) -> ApiResult {
+    let form = parse_form_urlencoded(body);
+    let email = form.get("email").and_then(|e| normalize_email(e));
+
+    // every request counts, per client and per address, to stop mail-bombing
+    let ip_key = format!("forgot:{}", client_key(stream));
+    let email_key = format!("email:{}", email.as_deref().unwrap_or(""));
+    if throttle.reserve(&[&ip_key, &email_key]).is_err() {
+        let html = b"<h1>Too many attempts, try again later</h1>";
+        return Ok(send_response(stream, 429, "text/html", html).await?);
+    }
+
+    let user = match &email {
+        Some(email) => {
+            sqlx::query_as::<_, (i64, String)>("SELECT id, username FROM users WHERE email = ?")
+                .bind(email)
+                .fetch_optional(&pool)
+                .await?
+        }
+        None => None,
+    };
+
+    if let (Some((user_id, username)), Some(email)) = (user, email) {
+        let minutes = config.password.reset_token_minutes;
+        let (token, _) = create_reset_token(&pool, user_id, minutes).await?;
+        let link = format!("{}/reset.html?token={}", config.mail.base_url.trim_end_matches('/'), token);
+        let message = Email {
+            to: email,
+            subject: "Reset your library password".to_string(),
+            body: format!(
+                "Hi {},\n\nSomeone asked to reset the password for your library account.\n\
+                 Open this link within {} minutes to choose a new one:\n\n{}\n\n\
+                 If it wasn't you, ignore this email; your password stays the same.\n",
+                username, minutes, link
+            ),
+        };
+
+        let mailer = mailer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mailer.send(&message).await {
+                tracing::error!(error = ?e, user_id, "Failed to send reset email");
+            }
+        });
+    }
+
+    let html = b"<h1>Check your email</h1>\
+        <p>If an account uses that address, a reset link is on its way.</p><a href=\"/\">Back to Login</a>";
+    Ok(send_response(stream, 200, "text/html", html).await?)
+}
+
+/// Issues a one-time reset token for `user_id`, returns (token, expires_at).
+/// Only the token's hash is stored.
+pub async fn create_reset_token(
+    pool: &SqlitePool,
+    user_id: i64,
+    lifetime_minutes: i64,
+) -> anyhow::Result<(String, String)> {
+    let token = generate_session_token();
+    let expires_at = sqlx::query_scalar::<_, String>(
+        "
+        INSERT INTO password_resets (token, user_id, created_at, expires_at)
+        VALUES (?, ?, datetime('now'), datetime('now', ?))
+        RETURNING expires_at
+        ",
+    )
+    .bind(hash_token(&token))
+    .bind(user_id)
+    .bind(format!("+{} minutes", lifetime_minutes))
+    .fetch_one(pool)
+    .await?;
+
+    Ok((token, expires_at))
+}
+
+/// Redeems a reset token from the reset form: sets the new password, signs
+/// out every session and lifts any lockout, then sends the user to log in
+async fn handle_reset_password(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    throttle: &Throttle,
+    passwords: &PasswordPolicy,
+    body: &str,
+) -> ApiResult {
+    let form = parse_form_urlencoded(body);
+    let token = hash_token(form.get("token").map(|s| s.as_str()).unwrap_or(""));
+    let password = form.get("password").map(|s| s.as_str()).unwrap_or("");
+
+    // guessing tokens costs the client the same backoff as guessing passwords
+    let ip_key = client_key(stream);
+    if throttle.reserve(&[&ip_key]).is_err() {
+        let html = b"<h1>Too many attempts, try again later</h1>";
+        return Ok(send_response(stream, 429, "text/html", html).await?);
+    }
+
+    let user = sqlx::query_as::<_, (i64, String)>(
+        "
+        SELECT u.id, u.username
+        FROM password_resets r
+        JOIN users u ON u.id = r.user_id
+        WHERE r.token = ? AND r.used_at IS NULL AND r.expires_at > datetime('now')
+        ",
+    )
+    .bind(&token)
+    .fetch_optional(&pool)
+    .await?;
+
+    let (user_id, username) = match user {
+        Some(user) => {
+            throttle.refund(&ip_key);
+            user
+        }
+        None => {
+            let html = b"<h1>Reset link is invalid, expired or already used</h1><a href=\"/\">Back</a>";
+            return Ok(send_response(stream, 403, "text/html", html).await?);
+        }
+    };
+
+    if let Err(message) = passwords.check(&username, password) {
+        let html = format!("<h1>{}</h1><a href=\"javascript:history.back()\">Back</a>", message);
+        return Ok(send_response(stream, 400, "text/html", html.as_bytes()).await?);
+    }
+    let hashed = hash_password(password).await?;
+
+    let mut tx = pool.begin().await?;
+
+    // claiming the token inside the transaction keeps it single-use under races
+    let claimed = sqlx::query(
+        "UPDATE password_resets SET used_at = datetime('now') WHERE token = ? AND used_at IS NULL",
+    )
+    .bind(&token)
+    .execute(&mut *tx)
+    .await?;
+    if claimed.rows_affected() == 0 {
+        let html = b"<h1>Reset link is invalid, expired or already used</h1><a href=\"/\">Back</a>";
+        return Ok(send_response(stream, 403, "text/html", html).await?);
+    }
+
+    // the users trigger signs out every session of the user
+    sqlx::query("UPDATE users SET password = ? WHERE id = ?")
+        .bind(&hashed)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM account_lockouts WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    throttle.clear(&format!("user:{}", username));
+
+    Ok(send_redirect(stream, "/").await?)
+}
@@ -0,0 +1,775 @@
+use sqlx::SqlitePool;
+
+use crate::account::create_reset_token;
+use crate::auth::{generate_session_token, hash_token};
+use crate::db::{clear_failed_logins, parse_loan_date, sync_book_availability};
+use crate::error::{ApiError, ApiResult};
+use crate::http::{Conn, Request};
+use crate::models::*;
+use crate::router::{BoxFuture, Ctx, Router};
+use crate::session::SessionStore;
+use crate::throttle::Throttle;
+use crate::two_factor;
+use crate::{send_html, send_json, send_response};
+
+pub fn routes(router: &mut Router) {
+    router
+        .role("admin")
+        .get("/admin/api/users", users)
+        .post("/admin/api/users/{id}/unlock", unlock_user)
+        .post("/admin/api/users/{id}/password-reset", reset_user_password)
+        .get("/admin/api/users/{id}/sessions", user_sessions)
+        .delete("/admin/api/users/{id}/sessions", revoke_user_sessions)
+        .delete("/admin/api/users/{id}/2fa", reset_user_two_factor)
+        .delete("/admin/api/sessions/{id}", revoke_session)
+        .get("/admin/api/invitations", invitations)
+        .post("/admin/api/invitations", create_invitation)
+        .delete("/admin/api/invitations/{id}", revoke_invitation)
+        .get("/admin/api/books", books)
+        .post("/admin/api/books", add_book)
+        .put("/admin/api/books/{bookid}", update_book)
+        .delete("/admin/api/books/{bookid}", delete_book)
+        .get("/admin/api/loans", loans)
+        .get("/admin/api/overdue", overdue);
+}
+
+//----------------------------------------------------------------------------------------------------------
+// routes — all gated on admin session by the router
+
+fn users(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(handle_admin_users(ctx.stream, ctx.pool))
+}
+
+fn unlock_user(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        match ctx.params.get::<i64>("id") {
+            Some(id) => handle_admin_unlock_user(ctx.stream, ctx.pool, ctx.throttle, id).await,
+            None => Err(ApiError::Validation("id must be a number".to_string())),
+        }
+    })
+}
+
+fn reset_user_password(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let lifetime_minutes = ctx.config.password.reset_token_minutes;
+        match ctx.params.get::<i64>("id") {
+            Some(id) => handle_admin_reset_password(ctx.stream, ctx.pool, id, lifetime_minutes).await,
+            None => Err(ApiError::Validation("id must be a number".to_string())),
+        }
+    })
+}
+
+fn user_sessions(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        match ctx.params.get::<i64>("id") {
+            Some(id) => handle_admin_user_sessions(ctx.stream, ctx.pool, ctx.sessions, id, ctx.request).await,
+            None => Err(ApiError::Validation("id must be a number".to_string())),
+        }
+    })
+}
+
+fn revoke_user_sessions(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        match ctx.params.get::<i64>("id") {
+            Some(id) => handle_admin_revoke_user_sessions(ctx.stream, ctx.pool, id).await,
+            None => Err(ApiError::Validation("id must be a number".to_string())),
+        }
+    })
+}
+
+fn reset_user_two_factor(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        match ctx.params.get::<i64>("id") {
+            Some(id) => handle_admin_reset_two_factor(ctx.stream, ctx.pool, id).await,
+            None => Err(ApiError::Validation("id must be a number".to_string())),
+        }
+    })
+}
+
+fn revoke_session(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        match ctx.params.get::<i64>("id") {
+            Some(id) => handle_admin_revoke_session(ctx.stream, ctx.pool, id).await,
+            None => Err(ApiError::Validation("id must be a number".to_string())),
+        }
+    })
+}
+
+fn invitations(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(handle_admin_invitations(ctx.stream, ctx.pool))
+}
+
+fn create_invitation(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let admin = ctx.username().to_string();
+        let lifetime_hours = ctx.config.invitations.lifetime_hours;
+        let body = ctx.request.body_str();
+        handle_admin_create_invitation(ctx.stream, ctx.pool, &admin, lifetime_hours, &body).await
+    })
+}
+
+fn revoke_invitation(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        match ctx.params.get::<i64>("id") {
+            Some(id) => handle_admin_revoke_invitation(ctx.stream, ctx.pool, id).await,
+            None => Err(ApiError::Validation("id must be a number".to_string())),
+        }
+    })
+}
+
+fn books(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(handle_admin_books(ctx.stream, ctx.pool))
+}
+
+fn add_book(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        handle_admin_add_book(ctx.stream, ctx.pool, &ctx.request.body_str()).await
+    })
+}
+
+fn update_book(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        match ctx.params.get::<i64>("bookid") {
+            Some(bookid) => {
+                handle_admin_update_book(ctx.stream, ctx.pool, bookid, &ctx.request.body_str()).await
+            }
+            None => Err(ApiError::Validation("bookid must be a number".to_string())),
+        }
+    })
+}
+
+fn delete_book(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        match ctx.params.get::<i64>("bookid") {
+            Some(bookid) => handle_admin_delete_book(ctx.stream, ctx.pool, bookid).await,
+            None => Err(ApiError::Validation("bookid must be a number".to_string())),
+        }
+    })
+}
+
+fn loans(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(handle_admin_loans(ctx.stream, ctx.pool))
+}
+
+fn overdue(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(handle_admin_overdue(ctx.stream, ctx.pool, ctx.config.loans.fine_per_day))
+}
+
+//----------------------------------------------------------------------------------------------------------
+// admin dashboard fns
+
+async fn handle_admin_users(
+    stream: &mut Conn,
+    pool: SqlitePool,
+) -> ApiResult {
+    let users = sqlx::query_as::<_, AdminUser>(
+    "
+    SELECT
+        u.id,
+        u.username,
+        u.role,
+        CASE WHEN a.locked_until > datetime('now') THEN a.locked_until END AS locked_until,
+        t.enabled_at IS NOT NULL AS two_factor
+    FROM users u
+    LEFT JOIN account_lockouts a ON a.user_id = u.id
+    LEFT JOIN user_totp t ON t.user_id = u.id
+    "
+)
+.fetch_all(&pool)
+.await?;
+
+
+    let json = serde_json::to_vec(&users)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Lifts a lockout and forgets the username's login backoff
+async fn handle_admin_unlock_user(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    throttle: &Throttle,
+    id: i64,
+) -> ApiResult {
+    let username = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("user {} not found", id)))?;
+
+    clear_failed_logins(&pool, id).await?;
+    throttle.clear(&format!("user:{}", username));
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": format!("{} unlocked", username)
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Removes a user's authenticator and recovery codes, e.g. after a lost
+/// phone. Their next login asks for the password only.
+async fn handle_admin_reset_two_factor(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    id: i64,
+) -> ApiResult {
+    let username = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("user {} not found", id)))?;
+
+    two_factor::remove(&pool, id).await?;
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": format!("Two-factor authentication reset for {}", username)
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Live sessions of one user
+async fn handle_admin_user_sessions(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    sessions: &SessionStore,
+    id: i64,
+    request: &Request,
+) -> ApiResult {
+    let exists = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_one(&pool)
+        .await?;
+    if exists == 0 {
+        return Err(ApiError::NotFound(format!("user {} not found", id)));
+    }
+
+    let list = sessions.list(&pool, id, request).await?;
+    let json = serde_json::to_vec(&list)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Signs a user out everywhere
+async fn handle_admin_revoke_user_sessions(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    id: i64,
+) -> ApiResult {
+    let username = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("user {} not found", id)))?;
+
+    let result = sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+        .bind(id)
+        .execute(&pool)
+        .await?;
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": format!("Signed out {} session(s) of {}", result.rows_affected(), username)
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+async fn handle_admin_revoke_session(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    id: i64,
+) -> ApiResult {
+    let result = sqlx::query("DELETE FROM sessions WHERE id = ?")
+        .bind(id)
+        .execute(&pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(format!("session {} not found", id)));
+    }
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": "Session signed out"
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Issues a one-time reset link for the user to choose a new password
+async fn handle_admin_reset_password(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    id: i64,
+    lifetime_minutes: i64,
+) -> ApiResult {
+    let exists = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_one(&pool)
+        .await?;
+    if exists == 0 {
+        return Err(ApiError::NotFound(format!("user {} not found", id)));
+    }
+
+    let (token, expires_at) = create_reset_token(&pool, id, lifetime_minutes).await?;
+
+    let response = serde_json::json!({
+        "token": token,
+        "expires_at": expires_at,
+        "reset_url": format!("/reset.html?token={}", token),
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_response(stream, 201, "application/json", &json).await?)
+}
+
+/// Invitations that can still be redeemed
+async fn handle_admin_invitations(
+    stream: &mut Conn,
+    pool: SqlitePool,
+) -> ApiResult {
+    let invitations = sqlx::query_as::<_, Invitation>(
+        "
+        SELECT i.id, i.role, u.username AS created_by, i.expires_at
+        FROM invitations i
+        JOIN users u ON u.id = i.created_by
+        WHERE i.used_at IS NULL AND i.expires_at > datetime('now')
+        ORDER BY i.expires_at
+        ",
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let json = serde_json::to_vec(&invitations)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Issues a single-use invitation; the new account gets `role` when it registers with the token.
+/// Only the token's hash is stored, so this response is the one place it appears.
+async fn handle_admin_create_invitation(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    admin: &str,
+    lifetime_hours: i64,
+    body: &str,
+) -> ApiResult {
+    let input: InvitationInput = if body.trim().is_empty() {
+        InvitationInput::default()
+    } else {
+        serde_json::from_str(body)
+            .map_err(|e| ApiError::Validation(format!("invalid invitation JSON: {}", e)))?
+    };
+    let role = input.role.unwrap_or_else(|| "admin".to_string());
+    if role != "admin" && role != "lender" {
+        return Err(ApiError::Validation("role must be admin or lender".to_string()));
+    }
+
+    let token = generate_session_token();
+    let (id, expires_at) = sqlx::query_as::<_, (i64, String)>(
+        "
+        INSERT INTO invitations (token_hash, role, created_by, created_at, expires_at)
+        SELECT ?, ?, id, datetime('now'), datetime('now', ?)
+        FROM users WHERE username = ?
+        RETURNING id, expires_at
+        ",
+    )
+    .bind(hash_token(&token))
+    .bind(&role)
+    .bind(format!("+{} hours", lifetime_hours))
+    .bind(admin)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound("admin user not found".to_string()))?;
+
+    let response = serde_json::json!({
+        "id": id,
+        "token": token,
+        "role": role,
+        "expires_at": expires_at,
+        "register_url": format!("/register.html?invite={}", token),
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_response(stream, 201, "application/json", &json).await?)
+}
+
+async fn handle_admin_revoke_invitation(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    id: i64,
+) -> ApiResult {
+    let result = sqlx::query("DELETE FROM invitations WHERE id = ? AND used_at IS NULL")
+        .bind(id)
+        .execute(&pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("no pending invitation with that id".to_string()));
+    }
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": "Invitation revoked"
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+async fn handle_admin_books(
+    stream: &mut Conn,
+    pool: SqlitePool,
+) -> ApiResult {
+
+    // First, sync all book availability counts with actual active loans
+    sync_book_availability(&pool).await?;
+
+    let rows = sqlx::query_as::<_, (
+        i64,            // bookid
+        String,         // title
+        String,         // author
+        String,         // isbn
+        Option<i64>,    // year_of_pub
+        Option<String>, // genre
+        i64,            // total_copies
+        i64             // available_copies
+    )>(
+        "
+        SELECT
+            bookid,
+            title,
+            author,
+            isbn,
+            year_of_pub,
+            genre,
+            total_copies,
+            available_copies
+        FROM books
+        "
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let books: Vec<AdminBook> = rows
+        .into_iter()
+        .map(|(bookid, title, author, isbn, year_of_pub, genre, total, available)| {
+            let checked_out = total - available;
+
+            let status = format!(
+                "{} available, {} checked out",
+                available, checked_out
+            );
+
+            AdminBook {
+                bookid,
+                title,
+                author,
+                isbn,
+                year_of_pub,
+                genre,
+                total_copies: total,
+                available_copies: available,
+                status,
+            }
+        })
+        .collect();
+
+    let json = serde_json::to_vec(&books)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+async fn handle_admin_loans(
+    stream: &mut Conn,
+    pool: SqlitePool,
+) -> ApiResult {
+    let rows = sqlx::query_as::<_, (i64, String, String, String, String, Option<String>)>(
+        "
+        SELECT
+            l.loanid,
+            u.username,
+            b.title,
+            l.checkout_date,
+            l.due_date,
+            l.return_date
+        FROM loans l
+        JOIN users u ON u.id = l.loaned_to_user_id
+        JOIN books b ON b.bookid = l.loaned_bookid
+        "
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let result = rows
+        .into_iter()
+        .map(|(loanid, username, title, checkout_date, due_date, return_date)| {
+            let status = calculate_loan_status(&due_date, return_date.as_deref())?;
+
+            Ok(AdminLoan {
+                loanid,
+                username,
+                title,
+                checkout_date,
+                due_date,
+                status,
+            })
+        })
+        .collect::<anyhow::Result<Vec<AdminLoan>>>()?;
+
+    let json = serde_json::to_vec(&result)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+//admin crud OPS
+async fn handle_admin_add_book(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    body: &str,
+) -> ApiResult {
+
+    let input = parse_book_input(body)?;
+
+    // check if book already exists by ISBN
+    let existing: Option<(i64, i64, i64)> =
+        sqlx::query_as(
+            "SELECT bookid, total_copies, available_copies FROM books WHERE isbn = ?"
+        )
+        .bind(&input.isbn)
+        .fetch_optional(&pool)
+        .await?;
+
+    match existing {
+        Some((bookid, _, _)) => {
+            // increase copies
+            sqlx::query(
+                "
+                UPDATE books
+                SET total_copies = total_copies + ?,
+                    available_copies = available_copies + ?
+                WHERE bookid = ?
+                "
+            )
+            .bind(input.copies)
+            .bind(input.copies)
+            .bind(bookid)
+            .execute(&pool)
+            .await?;
+
+            Ok(send_html(stream, b"Book exists - copies increased").await?)
+        }
+
+        None => {
+            // insert new book
+            sqlx::query(
+                "
+                INSERT INTO books
+                (title, author, isbn, year_of_pub, genre, total_copies, available_copies)
+                VALUES (?, ?, ?, ?, ?, ?, ?)
+                "
+            )
+            .bind(&input.title)
+            .bind(&input.author)
+            .bind(&input.isbn)
+            .bind(input.year_of_pub)
+            .bind(&input.genre)
+            .bind(input.copies)
+            .bind(input.copies)
+            .execute(&pool)
+            .await?;
+
+            Ok(send_html(stream, b"Book added successfully").await?)
+        }
+    }
+}
+
+async fn handle_admin_update_book(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    bookid: i64,
+    body: &str,
+) -> ApiResult {
+
+    let input = parse_book_input(body)?;
+
+    // fetch current counts
+    let (total, available): (i64, i64) = sqlx::query_as(
+        "SELECT total_copies, available_copies FROM books WHERE bookid = ?"
+    )
+    .bind(bookid)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(format!("book {} not found", bookid)))?;
+
+    let checked_out = total - available;
+
+    if input.copies < checked_out {
+        return Err(ApiError::Conflict(
+            "Cannot reduce total copies below number currently checked out".to_string(),
+        ));
+    }
+
+    let new_available = input.copies - checked_out;
+
+    sqlx::query(
+        "
+        UPDATE books
+        SET title = ?, author = ?, isbn = ?, year_of_pub = ?, genre = ?,
+            total_copies = ?, available_copies = ?
+        WHERE bookid = ?
+        "
+    )
+    .bind(&input.title)
+    .bind(&input.author)
+    .bind(&input.isbn)
+    .bind(input.year_of_pub)
+    .bind(&input.genre)
+    .bind(input.copies)
+    .bind(new_available)
+    .bind(bookid)
+    .execute(&pool)
+    .await?;
+
+    Ok(send_html(stream, b"Book updated successfully").await?)
+}
+
+
+async fn handle_admin_delete_book(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    bookid: i64,
+) -> ApiResult {
+
+    // Check active loans
+    let active_loans: i64 = sqlx::query_scalar(
+        "
+        SELECT COUNT(*)
+        FROM loans
+        WHERE loaned_bookid = ?
+        AND return_date IS NULL
+        "
+    )
+    .bind(bookid)
+    .fetch_one(&pool)
+    .await?;
+
+    if active_loans > 0 {
+        return Err(ApiError::Conflict(format!(
+            "Cannot delete book {}: {} active loan(s) exist",
+            bookid, active_loans
+        )));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    // 1️⃣ Delete historical loans
+    sqlx::query(
+        "
+        DELETE FROM loans
+        WHERE loaned_bookid = ?
+        "
+    )
+    .bind(bookid)
+    .execute(&mut *tx)
+    .await?;
+
+    let deleted = sqlx::query(
+        "
+        DELETE FROM books
+        WHERE bookid = ?
+        "
+    )
+    .bind(bookid)
+    .execute(&mut *tx)
+    .await?;
+
+    if deleted.rows_affected() == 0 {
+        // dropping tx rolls back
+        return Err(ApiError::NotFound(format!("book {} not found", bookid)));
+    }
+
+    tx.commit().await?;
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": "Book and related loan history deleted"
+    });
+
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+async fn handle_admin_overdue(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    fine_per_day: i64,
+) -> ApiResult {
+
+    let rows = sqlx::query_as::<_, (String, String, String)>(
+        "
+        SELECT
+            u.username,
+            b.title,
+            l.due_date
+        FROM loans l
+        JOIN users u ON u.id = l.loaned_to_user_id
+        JOIN books b ON b.bookid = l.loaned_bookid
+        WHERE l.return_date IS NULL
+          AND date(l.due_date) < date('now')
+        "
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let today = chrono::Utc::now().date_naive();
+
+    let result = rows
+        .into_iter()
+        .map(|(username, title, due_date)| {
+            let due = parse_loan_date(&due_date)?;
+            let days = (today - due).num_days();
+
+            Ok(serde_json::json!({
+                "username": username,
+                "title": title,
+                "due_date": due_date,
+                "days_overdue": days,
+                "fine": days * fine_per_day
+            }))
+        })
+        .collect::<anyhow::Result<Vec<serde_json::Value>>>()?;
+
+    let json = serde_json::to_vec(&result)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Parses and validates the JSON body shared by add and update
+fn parse_book_input(body: &str) -> ApiResult<AdminBookInput> {
+    let input: AdminBookInput = serde_json::from_str(body)
+        .map_err(|e| ApiError::Validation(format!("invalid book JSON: {}", e)))?;
+
+    if input.title.trim().is_empty()
+        || input.author.trim().is_empty()
+        || input.isbn.trim().is_empty()
+        || input.copies <= 0
+    {
+        return Err(ApiError::Validation(
+            "title, author and isbn are required and copies must be > 0".to_string(),
+        ));
+    }
+
+    Ok(input)
+}
+
+fn calculate_loan_status(
+    due_date: &str,
+    return_date: Option<&str>,
+) -> anyhow::Result<String> {
+    if return_date.is_some() {
+        return Ok("Returned".to_string());
+    }
+
+    let today = chrono::Utc::now().date_naive();
+    let due = parse_loan_date(due_date)?;
+
+    if due < today {
+        Ok("Overdue".to_string())
+    } else {
+        Ok("Borrowed".to_string())
+    }
+}
\ No newline at end of file
@@ -1,12 +1,38 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
 use bcrypt::{hash, verify, DEFAULT_COST};
 use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::config::PasswordConfig;
+
+/// bcrypt ignores everything past this many bytes
+const MAX_PASSWORD_BYTES: usize = 72;
+
+// bcrypt at DEFAULT_COST takes a good fraction of a second of CPU, so every
+// hash and verify runs on the blocking pool rather than a runtime worker
 
-pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
-    hash(password, DEFAULT_COST)
+pub async fn hash_password(password: &str) -> anyhow::Result<String> {
+    let password = password.to_string();
+    Ok(tokio::task::spawn_blocking(move || hash(password, DEFAULT_COST)).await??)
 }
 
-pub fn verify_password(password: &str, hash: &str) -> Result<bool, bcrypt::BcryptError> {
-    verify(password, hash)
+pub async fn verify_password(password: &str, hash: &str) -> anyhow::Result<bool> {
+    let (password, hash) = (password.to_string(), hash.to_string());
+    Ok(tokio::task::spawn_blocking(move || verify(password, &hash)).await??)
+}
+
+/// Spends the same time as a real `verify_password` when there is no hash to
+/// check against, so response timing doesn't reveal whether a user exists
+pub async fn verify_dummy_password(password: &str) {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let password = password.to_string();
+    let _ = tokio::task::spawn_blocking(move || {
+        let dummy = DUMMY.get_or_init(|| hash("not-a-real-password", DEFAULT_COST).unwrap_or_default());
+        verify(password, dummy)
+    })
+    .await;
 }
 
 
@@ -15,3 +41,74 @@ pub fn generate_session_token() -> String {
     let bytes: Vec<u8> = (0..32).map(|_| rng.gen::<u8>()).collect();
     bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
+
+/// SHA-256 of a random token, hex encoded. Tokens are stored only in this
+/// form so a leaked database can't be replayed; they're long and random, so
+/// a fast unsalted hash is enough.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Trims and lower-cases an address, None if it doesn't look like one.
+/// Also rules out anything that could inject SMTP headers.
+pub fn normalize_email(input: &str) -> Option<String> {
+    let email = input.trim().to_lowercase();
+    let (local, domain) = email.split_once('@')?;
+    let valid = email.len() <= 254
+        && !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains('@')
+        && email
+            .chars()
+            .all(|c| !c.is_whitespace() && !c.is_control() && !matches!(c, '<' | '>' | ',' | ';' | '"'));
+    valid.then_some(email)
+}
+
+/// Strength rules applied whenever a password is set
+pub struct PasswordPolicy {
+    min_length: usize,
+    /// lower-cased entries of the breached-password list
+    breached: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    /// Reads the breached-password list, if one is configured
+    pub fn load(config: &PasswordConfig) -> anyhow::Result<PasswordPolicy> {
+        let breached = match &config.breached_list {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("cannot read password.breached_list {}: {}", path, e))?
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(PasswordPolicy {
+            min_length: config.min_length,
+            breached,
+        })
+    }
+
+    /// Err with a message fit to show the user if `password` is too weak
+    pub fn check(&self, username: &str, password: &str) -> Result<(), String> {
+        if password.chars().count() < self.min_length {
+            return Err(format!("Password must be at least {} characters", self.min_length));
+        }
+        if password.len() > MAX_PASSWORD_BYTES {
+            return Err(format!("Password must be at most {} bytes", MAX_PASSWORD_BYTES));
+        }
+        if !username.is_empty() && password.eq_ignore_ascii_case(username) {
+            return Err("Password must not be the same as the username".to_string());
+        }
+        if self.breached.contains(&password.to_lowercase()) {
+            return Err("Password appears in a list of breached passwords, choose another".to_string());
+        }
+        Ok(())
+    }
+}
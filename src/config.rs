@@ -0,0 +1,798 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::http::{KeepAlive, Limits, Timeouts};
+
+/// Settings that vary per deployment. Built from, in increasing priority:
+/// built-in defaults, a TOML file, `LIBRARY_*` environment variables, CLI flags.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    pub database: DatabaseConfig,
+    pub session: SessionConfig,
+    pub login: LoginConfig,
+    pub password: PasswordConfig,
+    pub invitations: InvitationConfig,
+    pub two_factor: TwoFactorConfig,
+    pub mail: MailConfig,
+    pub metrics: MetricsConfig,
+    pub loans: LoanConfig,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_addr: String,
+    /// connections served at once; further clients wait in the listen backlog
+    pub max_connections: usize,
+    pub max_header_bytes: usize,
+    pub max_body_bytes: usize,
+    pub idle_timeout_secs: u64,
+    pub max_requests_per_connection: usize,
+    pub request_timeout_secs: u64,
+    pub write_timeout_secs: u64,
+    /// how long in-flight connections get to finish after SIGINT/SIGTERM
+    pub shutdown_grace_secs: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `tracing` filter, e.g. `info` or `info,library=debug`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// one readable line per event
+    Text,
+    /// one JSON object per line, for log pipelines
+    Json,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// SQLite file, created if missing; `:memory:` for a throwaway database
+    pub path: String,
+    /// `sqlite:` URL used instead of `path`, e.g. `sqlite://data/library.db?mode=rwc`
+    pub url: Option<String>,
+    pub max_connections: u32,
+    /// how long a statement waits for another connection's write lock
+    pub busy_timeout_ms: u64,
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// apply pending schema migrations at startup; when off they are only
+    /// reported, and `/readyz` fails until `library migrate up` is run
+    pub auto_migrate: bool,
+}
+
+/// SQLite `journal_mode`; ignored for in-memory databases
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalMode {
+    /// readers don't block the writer or each other
+    Wal,
+    Delete,
+    Truncate,
+    Persist,
+}
+
+/// SQLite `synchronous`: how often writes are flushed to disk
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    /// with WAL, durable except for the last commits before a power loss
+    Normal,
+    Full,
+    Extra,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// a session expires after this long without a request
+    pub lifetime_hours: i64,
+    /// idle lifetime instead of `lifetime_hours` when "remember me" is ticked
+    pub remember_days: i64,
+    /// how often expired sessions are deleted
+    pub sweep_interval_secs: u64,
+    /// secret for hashing session tokens at rest, created on first start
+    pub key_file: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginConfig {
+    /// failed passwords in a row before the account is locked
+    pub max_failed_attempts: i64,
+    pub lockout_minutes: i64,
+    /// failures per client IP / username before backoff kicks in
+    pub throttle_free_attempts: u32,
+    pub throttle_max_backoff_secs: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordConfig {
+    pub min_length: usize,
+    /// newline-separated list of known-breached passwords, e.g. a top-100k list
+    pub breached_list: Option<String>,
+    /// how long an admin-issued reset link stays valid
+    pub reset_token_minutes: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct InvitationConfig {
+    /// how long a staff invitation link stays valid
+    pub lifetime_hours: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwoFactorConfig {
+    /// admins must enrol in TOTP before the admin API lets them in
+    pub required_for_admins: bool,
+    /// name shown next to the account in authenticator apps
+    pub issuer: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    /// SMTP relay, e.g. `127.0.0.1:25`; unset prints outgoing mail to stdout
+    pub smtp_addr: Option<String>,
+    pub from: String,
+    /// public URL of the site, used to build links in emails
+    pub base_url: String,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// bearer token a scraper can use instead of an admin session
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoanConfig {
+    pub period_days: i64,
+    /// late fee charged per overdue day, in rupees
+    pub fine_per_day: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// e.g. `127.0.0.1:8081`; plain HTTP requests here get a 301 to https
+    pub redirect_addr: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let limits = Limits::default();
+        let keep_alive = KeepAlive::default();
+        let timeouts = Timeouts::default();
+        ServerConfig {
+            bind_addr: "127.0.0.1:8080".to_string(),
+            max_connections: 256,
+            max_header_bytes: limits.max_header_bytes,
+            max_body_bytes: limits.max_body_bytes,
+            idle_timeout_secs: keep_alive.idle_timeout.as_secs(),
+            max_requests_per_connection: keep_alive.max_requests,
+            request_timeout_secs: timeouts.read.as_secs(),
+            write_timeout_secs: timeouts.write.as_secs(),
+            shutdown_grace_secs: 10,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            path: "test.db".to_string(),
+            url: None,
+            max_connections: 10,
+            busy_timeout_ms: 5000,
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Normal,
+            auto_migrate: true,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            lifetime_hours: 24,
+            remember_days: 30,
+            sweep_interval_secs: 300,
+            key_file: "session.key".to_string(),
+        }
+    }
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            max_failed_attempts: 5,
+            lockout_minutes: 15,
+            throttle_free_attempts: 3,
+            throttle_max_backoff_secs: 300,
+        }
+    }
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        PasswordConfig {
+            min_length: 8,
+            breached_list: None,
+            reset_token_minutes: 60,
+        }
+    }
+}
+
+impl Default for InvitationConfig {
+    fn default() -> Self {
+        InvitationConfig { lifetime_hours: 72 }
+    }
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        TwoFactorConfig {
+            required_for_admins: false,
+            issuer: "Library".to_string(),
+        }
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            smtp_addr: None,
+            from: "library@localhost".to_string(),
+            base_url: "http://127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+impl Default for LoanConfig {
+    fn default() -> Self {
+        LoanConfig {
+            period_days: 14,
+            fine_per_day: 10,
+        }
+    }
+}
+
+impl LoginConfig {
+    pub fn throttle_max_backoff(&self) -> Duration {
+        Duration::from_secs(self.throttle_max_backoff_secs)
+    }
+}
+
+impl ServerConfig {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_header_bytes: self.max_header_bytes,
+            max_body_bytes: self.max_body_bytes,
+        }
+    }
+
+    pub fn keep_alive(&self) -> KeepAlive {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(self.idle_timeout_secs),
+            max_requests: self.max_requests_per_connection,
+        }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            read: Duration::from_secs(self.request_timeout_secs),
+            write: Duration::from_secs(self.write_timeout_secs),
+        }
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+}
+
+impl DatabaseConfig {
+    /// Whether the database lives only as long as the process
+    pub fn in_memory(&self) -> bool {
+        match &self.url {
+            Some(url) => url.contains(":memory:") || url.contains("mode=memory"),
+            None => self.path == ":memory:",
+        }
+    }
+
+    /// `url`, or `path` when no URL is set; for messages
+    pub fn location(&self) -> &str {
+        self.url.as_deref().unwrap_or(&self.path)
+    }
+
+    pub fn busy_timeout(&self) -> Duration {
+        Duration::from_millis(self.busy_timeout_ms)
+    }
+}
+
+impl SessionConfig {
+    /// Seconds a session stays alive after its last request
+    pub fn lifetime_secs(&self, remember: bool) -> i64 {
+        if remember {
+            self.remember_days * 24 * 60 * 60
+        } else {
+            self.lifetime_hours * 60 * 60
+        }
+    }
+
+    pub fn sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.sweep_interval_secs)
+    }
+}
+
+/// Longest idle lifetime of a session, a year
+const MAX_SESSION_HOURS: i64 = 365 * 24;
+
+/// Longest "remember me" lifetime, the most browsers keep a cookie for
+const MAX_REMEMBER_DAYS: i64 = 400;
+
+/// What the binary was asked to do
+pub enum Command {
+    Serve,
+    /// Create an admin account (password from LIBRARY_ADMIN_PASSWORD or stdin) and exit
+    CreateAdmin { username: String },
+    /// Run a local SMTP stand-in that prints every message it receives
+    FakeSmtp { addr: String },
+    /// Inspect or change the schema version, then print the migration status
+    Migrate(MigrateAction),
+}
+
+pub enum MigrateAction {
+    Status,
+    /// apply every pending migration
+    Up,
+    /// revert the newest `steps` applied migrations
+    Down { steps: usize },
+}
+
+const USAGE: &str = "\
+Usage: library [OPTIONS]
+       library [OPTIONS] create-admin <USERNAME>
+       library [OPTIONS] migrate <status|up|down [STEPS]>
+       library fake-smtp <ADDR>
+
+Commands:
+  create-admin <USERNAME> create an admin account and exit; the password is
+                          read from LIBRARY_ADMIN_PASSWORD or the first line of stdin
+  migrate status          list migrations and when each was applied
+  migrate up              apply pending migrations
+  migrate down [STEPS]    roll back the newest STEPS migrations (default 1)
+  fake-smtp <ADDR>        run a local SMTP stand-in that prints received mail,
+                          e.g. fake-smtp 127.0.0.1:2525 with --smtp 127.0.0.1:2525
+
+Options:
+  --config <FILE>        TOML config file (default: library.toml if present)
+  --bind <ADDR>          listen address, e.g. 127.0.0.1:8080
+  --max-connections <N>  connections served concurrently
+  --database <FILE>      SQLite database file, or :memory:
+  --database-url <URL>   sqlite: URL, used instead of --database
+  --db-max-connections <N> size of the SQLite connection pool
+  --db-journal-mode <MODE> wal, delete, truncate or persist
+  --no-migrate           don't apply pending migrations at startup
+  --session-hours <N>    idle session lifetime in hours
+  --loan-days <N>        loan period in days
+  --fine-per-day <N>     late fee per overdue day
+  --tls-cert <FILE>      PEM certificate chain (enables HTTPS with --tls-key)
+  --tls-key <FILE>       PEM private key
+  --http-redirect <ADDR> plain HTTP listener that redirects to HTTPS
+  --shutdown-grace <N>   seconds to drain connections on shutdown
+  --smtp <ADDR>          SMTP relay for outgoing mail (default: print to stdout)
+  --log-level <FILTER>   log filter, e.g. info or debug (default: info)
+  --log-format <FORMAT>  text or json
+  --metrics-token <TOKEN> bearer token for /metrics (prefer LIBRARY_METRICS_TOKEN)
+  -h, --help             print this help";
+
+impl Config {
+    /// Loads the config and command from the process environment and arguments
+    pub fn load() -> anyhow::Result<(Config, Command)> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        Config::from_sources(&args, |key| std::env::var(key).ok())
+    }
+
+    fn from_sources(
+        args: &[String],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<(Config, Command)> {
+        let (flags, positional) = parse_flags(args)?;
+
+        let command = match positional.as_slice() {
+            [] => Command::Serve,
+            [cmd, username] if cmd == "create-admin" => Command::CreateAdmin {
+                username: username.clone(),
+            },
+            [cmd, addr] if cmd == "fake-smtp" => Command::FakeSmtp { addr: addr.clone() },
+            [cmd, action] if cmd == "migrate" && action == "status" => Command::Migrate(MigrateAction::Status),
+            [cmd, action] if cmd == "migrate" && action == "up" => Command::Migrate(MigrateAction::Up),
+            [cmd, action] if cmd == "migrate" && action == "down" => {
+                Command::Migrate(MigrateAction::Down { steps: 1 })
+            }
+            [cmd, action, steps] if cmd == "migrate" && action == "down" => Command::Migrate(MigrateAction::Down {
+                steps: parse_unsigned(steps).map_err(|e| anyhow::anyhow!("migrate down: {}", e))?,
+            }),
+            _ => anyhow::bail!("unexpected arguments {:?}\n\n{}", positional, USAGE),
+        };
+
+        // file: --config beats LIBRARY_CONFIG beats ./library.toml (only if present)
+        let explicit = flags
+            .iter()
+            .rev()
+            .find(|(k, _)| k == "config")
+            .map(|(_, v)| v.clone())
+            .or_else(|| env("LIBRARY_CONFIG"));
+        let mut config = match explicit {
+            Some(path) => Config::from_file(&path)?,
+            None if std::path::Path::new("library.toml").exists() => Config::from_file("library.toml")?,
+            None => Config::default(),
+        };
+
+        // env, then flags, each overriding what came before
+        let env_overrides = [
+            ("bind", "LIBRARY_BIND_ADDR"),
+            ("max-connections", "LIBRARY_MAX_CONNECTIONS"),
+            ("database", "LIBRARY_DATABASE"),
+            ("database-url", "LIBRARY_DATABASE_URL"),
+            ("db-max-connections", "LIBRARY_DB_MAX_CONNECTIONS"),
+            ("db-journal-mode", "LIBRARY_DB_JOURNAL_MODE"),
+            ("no-migrate", "LIBRARY_NO_MIGRATE"),
+            ("session-hours", "LIBRARY_SESSION_HOURS"),
+            ("loan-days", "LIBRARY_LOAN_DAYS"),
+            ("fine-per-day", "LIBRARY_FINE_PER_DAY"),
+            ("tls-cert", "LIBRARY_TLS_CERT"),
+            ("tls-key", "LIBRARY_TLS_KEY"),
+            ("http-redirect", "LIBRARY_HTTP_REDIRECT_ADDR"),
+            ("shutdown-grace", "LIBRARY_SHUTDOWN_GRACE_SECS"),
+            ("smtp", "LIBRARY_SMTP_ADDR"),
+            ("log-level", "LIBRARY_LOG_LEVEL"),
+            ("log-format", "LIBRARY_LOG_FORMAT"),
+            ("metrics-token", "LIBRARY_METRICS_TOKEN"),
+        ];
+        for (key, var) in env_overrides {
+            if let Some(value) = env(var) {
+                config.apply(key, &value).map_err(|e| anyhow::anyhow!("{}: {}", var, e))?;
+            }
+        }
+        for (key, value) in &flags {
+            if key != "config" {
+                config.apply(key, value).map_err(|e| anyhow::anyhow!("--{}: {}", key, e))?;
+            }
+        }
+
+        config.validate()?;
+        Ok((config, command))
+    }
+
+    fn from_file(path: &str) -> anyhow::Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("cannot read config file {}: {}", path, e))?;
+        toml::from_str(&text).map_err(|e| anyhow::anyhow!("invalid config file {}: {}", path, e))
+    }
+
+    /// Sets one override by its flag name
+    fn apply(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        match key {
+            "bind" => self.server.bind_addr = value.to_string(),
+            "max-connections" => self.server.max_connections = parse_unsigned(value)?,
+            "database" => self.database.path = value.to_string(),
+            "database-url" => self.database.url = Some(value.to_string()),
+            "db-max-connections" => self.database.max_connections = parse_unsigned(value)?,
+            "db-journal-mode" => {
+                self.database.journal_mode = match value {
+                    "wal" => JournalMode::Wal,
+                    "delete" => JournalMode::Delete,
+                    "truncate" => JournalMode::Truncate,
+                    "persist" => JournalMode::Persist,
+                    _ => anyhow::bail!("expected wal, delete, truncate or persist, got {:?}", value),
+                }
+            }
+            "no-migrate" => self.database.auto_migrate = !parse_bool(value)?,
+            "session-hours" => self.session.lifetime_hours = parse_number(value)?,
+            "loan-days" => self.loans.period_days = parse_number(value)?,
+            "fine-per-day" => self.loans.fine_per_day = parse_number(value)?,
+            "tls-cert" => self.tls_mut().cert_path = value.to_string(),
+            "tls-key" => self.tls_mut().key_path = value.to_string(),
+            "http-redirect" => self.tls_mut().redirect_addr = Some(value.to_string()),
+            "shutdown-grace" => self.server.shutdown_grace_secs = parse_unsigned(value)?,
+            "smtp" => self.mail.smtp_addr = Some(value.to_string()),
+            "log-level" => self.log.level = value.to_string(),
+            "metrics-token" => self.metrics.token = Some(value.to_string()),
+            "log-format" => {
+                self.log.format = match value {
+                    "text" => LogFormat::Text,
+                    "json" => LogFormat::Json,
+                    _ => anyhow::bail!("expected text or json, got {:?}", value),
+                }
+            }
+            _ => anyhow::bail!("unknown option\n\n{}", USAGE),
+        }
+        Ok(())
+    }
+
+    fn tls_mut(&mut self) -> &mut TlsConfig {
+        self.tls.get_or_insert_with(|| TlsConfig {
+            cert_path: String::new(),
+            key_path: String::new(),
+            redirect_addr: None,
+        })
+    }
+
+    /// Rejects settings the server can't run with, naming the offending key
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.server.bind_addr.parse::<SocketAddr>().is_err() {
+            anyhow::bail!("server.bind_addr {:?} is not a valid socket address", self.server.bind_addr);
+        }
+        if self.server.max_connections == 0 {
+            anyhow::bail!("server.max_connections must be > 0");
+        }
+        if self.server.request_timeout_secs == 0 || self.server.write_timeout_secs == 0 {
+            anyhow::bail!("server.request_timeout_secs and server.write_timeout_secs must be > 0");
+        }
+        if self.server.max_header_bytes == 0 || self.server.max_body_bytes == 0 {
+            anyhow::bail!("server.max_header_bytes and server.max_body_bytes must be > 0");
+        }
+        if self.server.idle_timeout_secs == 0 || self.server.max_requests_per_connection == 0 {
+            anyhow::bail!("server.idle_timeout_secs and server.max_requests_per_connection must be > 0");
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.level) {
+            anyhow::bail!("log.level {:?} is not a valid filter: {}", self.log.level, e);
+        }
+        if self.database.path.trim().is_empty() {
+            anyhow::bail!("database.path must not be empty");
+        }
+        if let Some(url) = &self.database.url {
+            if !url.starts_with("sqlite:") {
+                anyhow::bail!("database.url {:?} must start with sqlite:", url);
+            }
+            if let Err(e) = url.parse::<sqlx::sqlite::SqliteConnectOptions>() {
+                anyhow::bail!("database.url {:?} is not a valid SQLite URL: {}", url, e);
+            }
+        }
+        if self.database.max_connections == 0 {
+            anyhow::bail!("database.max_connections must be > 0");
+        }
+        if self.session.lifetime_hours <= 0 || self.session.remember_days <= 0 {
+            anyhow::bail!("session.lifetime_hours and session.remember_days must be > 0");
+        }
+        // beyond these the lifetime in seconds could overflow, and browsers
+        // cap a cookie's Max-Age at 400 days anyway
+        if self.session.lifetime_hours > MAX_SESSION_HOURS {
+            anyhow::bail!("session.lifetime_hours must be at most {}", MAX_SESSION_HOURS);
+        }
+        if self.session.remember_days > MAX_REMEMBER_DAYS {
+            anyhow::bail!("session.remember_days must be at most {}", MAX_REMEMBER_DAYS);
+        }
+        if self.session.sweep_interval_secs == 0 {
+            anyhow::bail!("session.sweep_interval_secs must be > 0");
+        }
+        if self.session.key_file.trim().is_empty() {
+            anyhow::bail!("session.key_file must not be empty");
+        }
+        if self.login.max_failed_attempts <= 0 || self.login.lockout_minutes <= 0 {
+            anyhow::bail!("login.max_failed_attempts and login.lockout_minutes must be > 0");
+        }
+        if self.login.throttle_max_backoff_secs == 0 {
+            anyhow::bail!("login.throttle_max_backoff_secs must be > 0");
+        }
+        // bcrypt only looks at the first 72 bytes
+        if self.password.min_length == 0 || self.password.min_length > 72 {
+            anyhow::bail!("password.min_length must be between 1 and 72");
+        }
+        if self.password.reset_token_minutes <= 0 {
+            anyhow::bail!("password.reset_token_minutes must be > 0");
+        }
+        if self.invitations.lifetime_hours <= 0 {
+            anyhow::bail!("invitations.lifetime_hours must be > 0");
+        }
+        if self.two_factor.issuer.trim().is_empty() || self.two_factor.issuer.contains(':') {
+            anyhow::bail!("two_factor.issuer must be non-empty and must not contain ':'");
+        }
+        if !self.mail.base_url.starts_with("http://") && !self.mail.base_url.starts_with("https://") {
+            anyhow::bail!("mail.base_url must start with http:// or https://");
+        }
+        if self.metrics.token.as_ref().is_some_and(|t| t.len() < 16) {
+            anyhow::bail!("metrics.token must be at least 16 characters");
+        }
+        if self.loans.period_days <= 0 {
+            anyhow::bail!("loans.period_days must be > 0");
+        }
+        if self.loans.fine_per_day < 0 {
+            anyhow::bail!("loans.fine_per_day must not be negative");
+        }
+        if let Some(tls) = &self.tls {
+            if tls.cert_path.is_empty() || tls.key_path.is_empty() {
+                anyhow::bail!("TLS needs both tls.cert_path and tls.key_path");
+            }
+            if let Some(addr) = &tls.redirect_addr {
+                if addr.parse::<SocketAddr>().is_err() {
+                    anyhow::bail!("tls.redirect_addr {:?} is not a valid socket address", addr);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `(flag, value)` pairs in the order given
+type Flags = Vec<(String, String)>;
+
+/// Flags that take no value; given bare they mean `true`
+const SWITCHES: &[&str] = &["no-migrate"];
+
+/// `--flag value` / `--flag=value` pairs plus any positional arguments;
+/// `--help` prints usage and exits
+fn parse_flags(args: &[String]) -> anyhow::Result<(Flags, Vec<String>)> {
+    let mut flags = Vec::new();
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        if arg == "-h" || arg == "--help" {
+            println!("{}", USAGE);
+            std::process::exit(0);
+        }
+        let name = match arg.strip_prefix("--") {
+            Some(name) => name,
+            None => {
+                positional.push(arg.clone());
+                continue;
+            }
+        };
+
+        let (key, value) = match name.split_once('=') {
+            Some((k, v)) => (k.to_string(), v.to_string()),
+            None if SWITCHES.contains(&name) => (name.to_string(), "true".to_string()),
+            None => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--{} needs a value", name))?;
+                (name.to_string(), value.clone())
+            }
+        };
+        flags.push((key, value));
+    }
+
+    Ok((flags, positional))
+}
+
+fn parse_number(value: &str) -> anyhow::Result<i64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{:?} is not a whole number", value))
+}
+
+fn parse_bool(value: &str) -> anyhow::Result<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => anyhow::bail!("{:?} is not true or false", value),
+    }
+}
+
+fn parse_unsigned<T: TryFrom<i64>>(value: &str) -> anyhow::Result<T> {
+    parse_number(value)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("{:?} must not be negative", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// A config file with `contents` that is removed when dropped
+    struct TempConfig(std::path::PathBuf);
+
+    impl TempConfig {
+        fn new(contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("library-test-{:016x}.toml", rand::random::<u64>()));
+            std::fs::write(&path, contents).unwrap();
+            TempConfig(path)
+        }
+
+        fn path(&self) -> String {
+            self.0.display().to_string()
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> anyhow::Result<Config> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let env: HashMap<String, String> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Config::from_sources(&args, |key| env.get(key).cloned()).map(|(config, _)| config)
+    }
+
+    #[test]
+    fn flags_beat_env_beat_the_file() {
+        let file = TempConfig::new("[session]\nlifetime_hours = 10\n[loans]\nperiod_days = 7\n");
+        let path = file.path();
+
+        let config = load(&["--config", &path], &[]).unwrap();
+        assert_eq!(config.session.lifetime_hours, 10);
+        assert_eq!(config.loans.period_days, 7);
+        // untouched keys keep their defaults
+        assert_eq!(config.session.remember_days, SessionConfig::default().remember_days);
+
+        let env = [("LIBRARY_SESSION_HOURS", "20")];
+        let config = load(&["--config", &path], &env).unwrap();
+        assert_eq!(config.session.lifetime_hours, 20);
+        assert_eq!(config.loans.period_days, 7);
+
+        let config = load(&["--config", &path, "--session-hours=30"], &env).unwrap();
+        assert_eq!(config.session.lifetime_hours, 30);
+
+        // LIBRARY_CONFIG names the file when --config doesn't
+        let config = load(&[], &[("LIBRARY_CONFIG", &path)]).unwrap();
+        assert_eq!(config.session.lifetime_hours, 10);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let file = TempConfig::new("[session]\nlifetime_hour = 10\n");
+        let error = load(&["--config", &file.path()], &[]).unwrap_err().to_string();
+        assert!(error.contains("lifetime_hour"), "{}", error);
+
+        let file = TempConfig::new("[sesion]\nlifetime_hours = 10\n");
+        assert!(load(&["--config", &file.path()], &[]).is_err());
+
+        let error = load(&["--session-hour", "10"], &[]).unwrap_err().to_string();
+        assert!(error.starts_with("--session-hour: unknown option"), "{}", error);
+    }
+
+    #[test]
+    fn session_lifetimes_are_bounded() {
+        let config = load(&["--session-hours", &MAX_SESSION_HOURS.to_string()], &[]).unwrap();
+        assert_eq!(config.session.lifetime_secs(false), MAX_SESSION_HOURS * 60 * 60);
+
+        let error = load(&["--session-hours", "9223372036854775807"], &[]).unwrap_err().to_string();
+        assert!(error.contains("session.lifetime_hours must be at most"), "{}", error);
+        assert!(load(&[], &[("LIBRARY_SESSION_HOURS", "0")]).is_err());
+
+        let file = TempConfig::new("[session]\nremember_days = 100000000000000\n");
+        let error = load(&["--config", &file.path()], &[]).unwrap_err().to_string();
+        assert!(error.contains("session.remember_days must be at most"), "{}", error);
+
+        let file = TempConfig::new(&format!("[session]\nremember_days = {}\n", MAX_REMEMBER_DAYS));
+        let config = load(&["--config", &file.path()], &[]).unwrap();
+        assert_eq!(config.session.lifetime_secs(true), MAX_REMEMBER_DAYS * 24 * 60 * 60);
+    }
+}
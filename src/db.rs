@@ -1,85 +1,262 @@
+use std::path::PathBuf;
+
 use sqlx::{
-    sqlite::{SqliteConnectOptions, SqlitePool},
-    Executor,
+    sqlite::{
+        SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteSynchronous,
+    },
+    Executor, Sqlite,
 };
 
-pub async fn get_db_pool() -> SqlitePool {
-    let options = SqliteConnectOptions::new()
-        .filename("test.db")
-        .create_if_missing(true);
+use crate::config::{DatabaseConfig, JournalMode, Synchronous};
 
-    let pool = SqlitePool::connect_with(options)
-        .await
-        .expect("Failed to connect to DB");
+/// Opens the configured database, creating the file if missing. The schema
+/// itself is managed by the migrations in `migrate`.
+pub async fn get_db_pool(config: &DatabaseConfig) -> anyhow::Result<SqlitePool> {
+    if let Some(file) = database_file(config) {
+        // sqlite only says "unable to open database file"
+        if let Some(parent) = file.parent().filter(|p| !p.as_os_str().is_empty()) {
+            if !parent.is_dir() {
+                anyhow::bail!(
+                    "cannot open database {}: directory {} does not exist",
+                    config.location(),
+                    parent.display()
+                );
+            }
+        }
+    }
+
+    let mut options = connect_options(config)?
+        .create_if_missing(true)
+        .foreign_keys(true)
+        .busy_timeout(config.busy_timeout())
+        .synchronous(match config.synchronous {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        });
 
-    // Enable foreign keys (off by default in SQLite)
-    pool.execute("PRAGMA foreign_keys = ON")
+    let mut pool_options = SqlitePoolOptions::new().max_connections(config.max_connections);
+    if config.in_memory() {
+        // every connection would otherwise see its own empty database, and
+        // the data is gone once the last connection closes
+        pool_options = pool_options
+            .max_connections(1)
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+    } else {
+        options = options.journal_mode(match config.journal_mode {
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+        });
+    }
+
+    pool_options
+        .connect_with(options)
         .await
-        .unwrap();
+        .map_err(|e| anyhow::anyhow!("cannot open database {}: {}", config.location(), e))
+}
 
-    // Users
-    pool.execute(
-        "
-        CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            username TEXT UNIQUE NOT NULL,
-            password TEXT NOT NULL,
-            role TEXT NOT NULL
-        )
-        ",
+fn connect_options(config: &DatabaseConfig) -> anyhow::Result<SqliteConnectOptions> {
+    match &config.url {
+        Some(url) => url
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid database.url {:?}: {}", url, e)),
+        None if config.in_memory() => Ok("sqlite::memory:".parse()?),
+        None => Ok(SqliteConnectOptions::new().filename(&config.path)),
+    }
+}
+
+/// The database file on disk; `None` for an in-memory database
+pub fn database_file(config: &DatabaseConfig) -> Option<PathBuf> {
+    if config.in_memory() {
+        return None;
+    }
+    let options = connect_options(config).ok()?;
+    Some(options.get_filename().into_owned())
+}
+
+pub async fn table_exists<'e, E>(executor: E, table: &str) -> Result<bool, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let count = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
     )
-    .await
-    .unwrap();
+    .bind(table)
+    .fetch_one(executor)
+    .await?;
+    Ok(count > 0)
+}
 
-    // Books
-    pool.execute(
-        "
-        CREATE TABLE IF NOT EXISTS books (
-            bookid INTEGER PRIMARY KEY AUTOINCREMENT,
-            title TEXT NOT NULL,
-            author TEXT NOT NULL,
-            isbn TEXT UNIQUE NOT NULL,
-            year_of_pub INTEGER,
-            genre TEXT,
-            total_copies INTEGER NOT NULL DEFAULT 0,
-            available_copies INTEGER NOT NULL DEFAULT 0
-        )
-        ",
+/// Adds a column to a table that was created by an older schema. Takes a
+/// connection so it can run inside a migration's transaction.
+pub async fn add_column_if_missing(
+    conn: &mut SqliteConnection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    if !column_exists(&mut *conn, table, column).await? {
+        conn.execute(format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition).as_str())
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn column_exists<'e, E>(executor: E, table: &str, column: &str) -> Result<bool, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let count = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?",
     )
-    .await
-    .unwrap();
+    .bind(table)
+    .bind(column)
+    .fetch_one(executor)
+    .await?;
+    Ok(count > 0)
+}
 
-    // Loans
-    pool.execute(
+/// Counts a login attempt as failed before its credentials are checked; false
+/// if the account is inside a lockout window, and nothing is counted. The
+/// `max_attempts`-th attempt locks the account for `lockout_minutes` and
+/// starts the count over. Check and count are one statement, so parallel
+/// attempts can't all get in before the lockout; a successful attempt undoes
+/// it all with `clear_failed_logins`.
+pub async fn reserve_login_attempt(
+    pool: &SqlitePool,
+    user_id: i64,
+    max_attempts: i64,
+    lockout_minutes: i64,
+) -> anyhow::Result<bool> {
+    let reserved = sqlx::query_scalar::<_, i64>(
         "
-        CREATE TABLE IF NOT EXISTS loans (
-            loanid INTEGER PRIMARY KEY AUTOINCREMENT,
-            loaned_to_user_id INTEGER NOT NULL,
-            loaned_bookid INTEGER NOT NULL,
-            checkout_date TEXT NOT NULL,
-            due_date TEXT NOT NULL,
-            return_date TEXT,
-            FOREIGN KEY(loaned_to_user_id) REFERENCES users(id),
-            FOREIGN KEY(loaned_bookid) REFERENCES books(bookid)
-        )
+        INSERT INTO account_lockouts (user_id, failed_attempts, locked_until)
+        VALUES (?1, CASE WHEN ?2 <= 1 THEN 0 ELSE 1 END, CASE WHEN ?2 <= 1 THEN datetime('now', ?3) END)
+        ON CONFLICT(user_id) DO UPDATE SET
+            failed_attempts = CASE WHEN failed_attempts + 1 >= ?2 THEN 0 ELSE failed_attempts + 1 END,
+            locked_until = CASE WHEN failed_attempts + 1 >= ?2 THEN datetime('now', ?3) ELSE locked_until END
+        WHERE locked_until IS NULL OR locked_until <= datetime('now')
+        RETURNING user_id
         ",
     )
-    .await
-    .unwrap();
+    .bind(user_id)
+    .bind(max_attempts)
+    .bind(format!("+{} minutes", lockout_minutes))
+    .fetch_optional(pool)
+    .await?;
+    Ok(reserved.is_some())
+}
+
+/// Resets the failure count and lifts any lockout
+pub async fn clear_failed_logins(pool: &SqlitePool, user_id: i64) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM account_lockouts WHERE user_id = ?")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
 
-    // Sessions
-    pool.execute(
+/// Parses a `YYYY-MM-DD` date stored in the loans table. A malformed row is
+/// an internal error, not a reason to take the connection down.
+pub fn parse_loan_date(value: &str) -> anyhow::Result<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|e| anyhow::anyhow!("invalid date {:?} in loans: {}", value, e))
+}
+
+/// Syncs available_copies for all books based on actual active loans
+pub async fn sync_book_availability(pool: &SqlitePool) -> anyhow::Result<()> {
+    // For each book, recalculate available_copies = total_copies - active_loans
+    sqlx::query(
         "
-        CREATE TABLE IF NOT EXISTS sessions (
-            token TEXT PRIMARY KEY,
-            username TEXT NOT NULL,
-            role TEXT NOT NULL,
-            expires_at TEXT NOT NULL
+        UPDATE books
+        SET available_copies = total_copies - (
+            SELECT COUNT(*)
+            FROM loans
+            WHERE loans.loaned_bookid = books.bookid
+            AND loans.return_date IS NULL
         )
-        ",
+        "
     )
-    .await
-    .unwrap();
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn memory_pool_with_user() -> (SqlitePool, i64) {
+        let config = DatabaseConfig {
+            path: ":memory:".to_string(),
+            ..DatabaseConfig::default()
+        };
+        let pool = get_db_pool(&config).await.unwrap();
+        crate::migrate::run(&pool).await.unwrap();
+        let id = sqlx::query_scalar::<_, i64>(
+            "INSERT INTO users (username, password, role) VALUES ('alice', 'x', 'lender') RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        (pool, id)
+    }
+
+    #[tokio::test]
+    async fn the_max_attempt_locks_until_cleared() {
+        let (pool, id) = memory_pool_with_user().await;
+        for _ in 0..3 {
+            assert!(reserve_login_attempt(&pool, id, 3, 15).await.unwrap());
+        }
+        assert!(!reserve_login_attempt(&pool, id, 3, 15).await.unwrap());
+        assert!(!reserve_login_attempt(&pool, id, 3, 15).await.unwrap());
+
+        clear_failed_logins(&pool, id).await.unwrap();
+        assert!(reserve_login_attempt(&pool, id, 3, 15).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn an_expired_lockout_starts_a_new_count() {
+        let (pool, id) = memory_pool_with_user().await;
+        sqlx::query(
+            "INSERT INTO account_lockouts (user_id, failed_attempts, locked_until) VALUES (?, 0, datetime('now', '-1 minutes'))",
+        )
+        .bind(id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert!(reserve_login_attempt(&pool, id, 2, 15).await.unwrap());
+        assert!(reserve_login_attempt(&pool, id, 2, 15).await.unwrap());
+        assert!(!reserve_login_attempt(&pool, id, 2, 15).await.unwrap());
+
+        // a limit of one locks on the very first attempt
+        let other = sqlx::query_scalar::<_, i64>(
+            "INSERT INTO users (username, password, role) VALUES ('bob', 'x', 'lender') RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(reserve_login_attempt(&pool, other, 1, 15).await.unwrap());
+        assert!(!reserve_login_attempt(&pool, other, 1, 15).await.unwrap());
+    }
 
-    pool
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn parallel_attempts_stop_at_the_limit() {
+        let (pool, id) = memory_pool_with_user().await;
+        let attempts = (0..20).map(|_| {
+            let pool = pool.clone();
+            tokio::spawn(async move { reserve_login_attempt(&pool, id, 5, 15).await.unwrap() })
+        });
+        let mut admitted = 0;
+        for attempt in attempts.collect::<Vec<_>>() {
+            admitted += attempt.await.unwrap() as usize;
+        }
+        assert_eq!(admitted, 5);
+    }
+}
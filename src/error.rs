@@ -0,0 +1,130 @@
+use serde_json::json;
+
+/// Error returned by route handlers. Everything except `Internal` is the
+/// client's fault and is reported back verbatim; `Internal` is logged and
+/// answered with a generic 500.
+#[derive(Debug)]
+pub enum ApiError {
+    NotFound(String),
+    Validation(String),
+    Conflict(String),
+    Unauthorized(String),
+    Forbidden(String),
+    TooManyRequests(String),
+    Internal(anyhow::Error),
+}
+
+pub type ApiResult<T = ()> = Result<T, ApiError>;
+
+impl ApiError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::Validation(_) => 400,
+            ApiError::Unauthorized(_) => 401,
+            ApiError::Forbidden(_) => 403,
+            ApiError::NotFound(_) => 404,
+            ApiError::Conflict(_) => 409,
+            ApiError::TooManyRequests(_) => 429,
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    /// Machine-readable code sent as the `error` field
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Validation(_) => "validation_error",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::Validation(m)
+            | ApiError::Unauthorized(m)
+            | ApiError::Forbidden(m)
+            | ApiError::NotFound(m)
+            | ApiError::Conflict(m)
+            | ApiError::TooManyRequests(m) => m.clone(),
+            // never leak internals to the client
+            ApiError::Internal(_) => "internal server error".to_string(),
+        }
+    }
+
+    /// JSON body: `{"error": code, "message": message}`
+    pub fn to_json(&self) -> Vec<u8> {
+        json!({
+            "error": self.code(),
+            "message": self.message(),
+        })
+        .to_string()
+        .into_bytes()
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Internal(e) => write!(f, "internal error: {:?}", e),
+            other => write!(f, "{}: {}", other.code(), other.message()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError::Internal(e)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::Database(db) if db.is_unique_violation() => {
+                ApiError::Conflict("a record with the same unique value already exists".to_string())
+            }
+            _ => ApiError::Internal(e.into()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn each_variant_maps_to_its_status_and_json_body() {
+        let cases = [
+            (ApiError::Validation("bad isbn".into()), 400, "validation_error", "bad isbn"),
+            (ApiError::Unauthorized("log in".into()), 401, "unauthorized", "log in"),
+            (ApiError::Forbidden("admins only".into()), 403, "forbidden", "admins only"),
+            (ApiError::NotFound("no such book".into()), 404, "not_found", "no such book"),
+            (ApiError::Conflict("already lent".into()), 409, "conflict", "already lent"),
+            (ApiError::TooManyRequests("slow down".into()), 429, "too_many_requests", "slow down"),
+            (
+                ApiError::Internal(anyhow::anyhow!("disk I/O error at /var/lib/library.db")),
+                500,
+                "internal_error",
+                "internal server error",
+            ),
+        ];
+
+        for (error, status, code, message) in cases {
+            assert_eq!(error.status(), status, "{error}");
+            let body: Value = serde_json::from_slice(&error.to_json()).unwrap();
+            assert_eq!(body, json!({ "error": code, "message": message }));
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+
+use crate::db;
+use crate::error::ApiResult;
+use crate::http::Conn;
+use crate::migrate;
+use crate::router::{BoxFuture, Ctx, Router};
+use crate::send_response;
+
+/// A check that hasn't answered by then counts as failed, so a wedged
+/// database can't hang the supervisor's probe
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn routes(router: &mut Router) {
+    router
+        .get("/healthz", healthz)
+        .get("/readyz", readyz);
+}
+
+//----------------------------------------------------------------------------------------------------------
+// routes — public, meant for a local supervisor
+
+fn healthz(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(handle_healthz(ctx.stream))
+}
+
+fn readyz(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let database_file = db::database_file(&ctx.config.database);
+        handle_readyz(ctx.stream, ctx.pool, database_file.as_deref()).await
+    })
+}
+
+//----------------------------------------------------------------------------------------------------------
+// health fns
+
+/// Liveness: answering at all means the process and its event loop are up
+async fn handle_healthz(stream: &mut Conn) -> ApiResult {
+    let body = json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION")
+    });
+    let json = serde_json::to_vec(&body)?;
+    Ok(send_response(stream, 200, "application/json", &json).await?)
+}
+
+/// Readiness: the database answers, is migrated to the version this build expects
+/// and its directory takes writes. 503 if any check fails. The endpoint is
+/// public, so it only says which checks failed; the reasons go to the log.
+async fn handle_readyz(stream: &mut Conn, pool: SqlitePool, database_file: Option<&Path>) -> ApiResult {
+    let database = check("database", async {
+        sqlx::query("SELECT 1").execute(&pool).await?;
+        Ok(())
+    })
+    .await;
+
+    let schema = check("schema", async {
+        let version = migrate::current_version(&pool).await?;
+        let expected = migrate::latest_version();
+        if version != expected {
+            anyhow::bail!("database is at schema version {}, expected {}", version, expected);
+        }
+        Ok(())
+    })
+    .await;
+
+    let disk = check("disk", probe_disk(database_file)).await;
+
+    let ready = [&database, &schema, &disk].iter().all(|c| c["status"] == "ok");
+    let body = json!({
+        "status": if ready { "ok" } else { "fail" },
+        "checks": {
+            "database": database,
+            "schema": schema,
+            "disk": disk
+        }
+    });
+    let json = serde_json::to_vec(&body)?;
+    let status = if ready { 200 } else { 503 };
+    Ok(send_response(stream, status, "application/json", &json).await?)
+}
+
+/// Runs one check under the timeout. Why it failed is logged, not returned.
+async fn check<F>(name: &str, run: F) -> Value
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    let status = match tokio::time::timeout(CHECK_TIMEOUT, run).await {
+        Ok(Ok(())) => "ok",
+        Ok(Err(e)) => {
+            tracing::warn!(check = name, error = %e, "Readiness check failed");
+            "fail"
+        }
+        Err(_) => {
+            tracing::warn!(check = name, timeout = ?CHECK_TIMEOUT, "Readiness check timed out");
+            "fail"
+        }
+    };
+    json!({ "status": status })
+}
+
+/// Writes and removes a small file next to the database. SQLite needs the
+/// directory writable too, for its journal. Nothing to check in memory. The
+/// name is unique per probe, so overlapping `/readyz` calls don't remove each
+/// other's files.
+async fn probe_disk(database_file: Option<&Path>) -> anyhow::Result<()> {
+    let Some(database_file) = database_file else {
+        return Ok(());
+    };
+    let probe = format!("{}.ready-probe-{:016x}", database_file.display(), rand::random::<u64>());
+    tokio::fs::write(&probe, b"ok")
+        .await
+        .map_err(|e| anyhow::anyhow!("cannot write {}: {}", probe, e))?;
+    tokio::fs::remove_file(&probe)
+        .await
+        .map_err(|e| anyhow::anyhow!("cannot remove {}: {}", probe, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn overlapping_disk_probes_all_pass() {
+        let directory = std::env::temp_dir().join(format!("library-probe-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&directory).unwrap();
+        let database_file = directory.join("library.db");
+
+        let probes = (0..16).map(|_| {
+            let database_file = database_file.clone();
+            tokio::spawn(async move { probe_disk(Some(&database_file)).await })
+        });
+        for probe in probes.collect::<Vec<_>>() {
+            probe.await.unwrap().unwrap();
+        }
+        // nothing is left behind
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+        std::fs::remove_dir(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_checks_only_say_so() {
+        let missing = std::env::temp_dir().join("library-no-such-dir").join("library.db");
+        assert_eq!(check("disk", probe_disk(Some(&missing))).await, json!({ "status": "fail" }));
+        assert_eq!(check("disk", probe_disk(None)).await, json!({ "status": "ok" }));
+    }
+}
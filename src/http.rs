@@ -0,0 +1,599 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::time::Sleep;
+
+use crate::response::Cookie;
+
+/// Size limits applied while reading a request off the socket
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub max_header_bytes: usize,
+    pub max_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_header_bytes: 16 * 1024,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// How long an idle connection is kept open and how many requests it may carry
+#[derive(Clone, Copy)]
+pub struct KeepAlive {
+    pub idle_timeout: Duration,
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+/// Deadlines for moving a request in and a response out. `read` bounds the
+/// whole request once its first byte has arrived (408 when exceeded); `write`
+/// bounds how long a response write may stall without progress.
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    pub read: Duration,
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            read: Duration::from_secs(10),
+            write: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Why a request could not be read
+#[derive(Debug)]
+pub enum HttpError {
+    Io(std::io::Error),
+    Malformed(&'static str),
+    HeadersTooLarge,
+    PayloadTooLarge,
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::Io(e) => write!(f, "io error: {}", e),
+            HttpError::Malformed(why) => write!(f, "malformed request: {}", why),
+            HttpError::HeadersTooLarge => write!(f, "request headers too large"),
+            HttpError::PayloadTooLarge => write!(f, "request body too large"),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<std::io::Error> for HttpError {
+    fn from(e: std::io::Error) -> Self {
+        HttpError::Io(e)
+    }
+}
+
+/// A fully read HTTP/1.1 request
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Case-insensitive header lookup, returns the first match
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Extracts a specific cookie value from the Cookie header(s)
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        let prefix = format!("{}=", name);
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .find_map(|cookie| cookie.trim().strip_prefix(&prefix).map(|v| v.to_string()))
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    /// HTTP/1.1 defaults to keep-alive, HTTP/1.0 must ask for it explicitly.
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection = self.header("connection").unwrap_or("").to_ascii_lowercase();
+        if self.version == "HTTP/1.0" {
+            connection.contains("keep-alive")
+        } else {
+            !connection.contains("close")
+        }
+    }
+
+    pub fn query_param(&self, key: &str) -> Option<String> {
+        crate::parse_query_param(&self.query, key)
+    }
+
+    /// Body as text; invalid UTF-8 is replaced rather than rejected
+    pub fn body_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+}
+
+/// Anything a connection can run over: a plain TCP socket or a TLS stream
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A client connection: the socket plus any bytes read past the last request
+/// (pipelined requests), and whether it stays open after the current response.
+pub struct Conn {
+    stream: Box<dyn Stream>,
+    buf: Vec<u8>,
+    secure: bool,
+    /// client address, when known (used for per-IP throttling)
+    pub peer_ip: Option<IpAddr>,
+    pub keep_alive: bool,
+    pub write_timeout: Duration,
+    /// id of the request being answered, echoed in `X-Request-Id`
+    pub request_id: Option<String>,
+    /// status of the last response sent, for the access log
+    pub status: Option<u16>,
+    /// set on the next response ahead of its own cookies, e.g. a session
+    /// cookie whose expiry slid forward
+    pub cookies: Vec<Cookie>,
+    /// answering a HEAD request: responses go out without their body
+    pub head_only: bool,
+    /// running while a write is stalled; reset whenever the socket accepts data
+    write_stall: Option<Pin<Box<Sleep>>>,
+}
+
+impl Conn {
+    /// `secure` is true when `stream` is TLS-encrypted
+    pub fn new<S: Stream + 'static>(stream: S, secure: bool) -> Self {
+        Conn {
+            stream: Box::new(stream),
+            buf: Vec::with_capacity(4096),
+            secure,
+            peer_ip: None,
+            keep_alive: false,
+            write_timeout: Timeouts::default().write,
+            request_id: None,
+            status: None,
+            cookies: Vec::new(),
+            head_only: false,
+            write_stall: None,
+        }
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Value for the `Connection` response header
+    pub fn connection_header(&self) -> &'static str {
+        if self.keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        }
+    }
+
+    /// Waits until the client starts sending a request (or one is already
+    /// buffered). Returns false if the peer closed the connection instead.
+    pub async fn wait_for_request(&mut self) -> std::io::Result<bool> {
+        if !self.buf.is_empty() {
+            return Ok(true);
+        }
+        match read_more(&mut self.stream, &mut self.buf).await {
+            Ok(n) => Ok(n > 0),
+            Err(HttpError::Io(e)) => Err(e),
+            Err(e) => Err(std::io::Error::other(e)),
+        }
+    }
+
+    /// Reads the next request, see [`read_request`]
+    pub async fn next_request(&mut self, limits: &Limits) -> Result<Option<Request>, HttpError> {
+        read_request(&mut self.stream, &mut self.buf, limits).await
+    }
+}
+
+impl Conn {
+    /// Turns a pending write into an error once it has stalled for `write_timeout`
+    fn poll_stalled<T>(&mut self, cx: &mut Context<'_>, poll: Poll<std::io::Result<T>>) -> Poll<std::io::Result<T>> {
+        if poll.is_ready() {
+            self.write_stall = None;
+            return poll;
+        }
+        let timeout = self.write_timeout;
+        let stall = self
+            .write_stall
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match stall.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.write_stall = None;
+                Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "response write timed out",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        self.poll_stalled(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_flush(cx);
+        self.poll_stalled(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_shutdown(cx);
+        self.poll_stalled(cx, poll)
+    }
+}
+
+/// Reads one request from the stream. `buf` carries bytes already read but not
+/// yet consumed; anything past the end of this request is left in it.
+/// Returns Ok(None) if the peer closed the connection before sending anything.
+pub async fn read_request<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<Option<Request>, HttpError>
+where
+    S: AsyncRead + Unpin,
+{
+    // read until the blank line that ends the header block
+    let header_end = loop {
+        if let Some(pos) = find_subslice(buf, b"\r\n\r\n") {
+            break pos;
+        }
+        if buf.len() > limits.max_header_bytes {
+            return Err(HttpError::HeadersTooLarge);
+        }
+        if read_more(stream, buf).await? == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(HttpError::Malformed("connection closed mid-headers"));
+        }
+    };
+    if header_end > limits.max_header_bytes {
+        return Err(HttpError::HeadersTooLarge);
+    }
+
+    let head = std::str::from_utf8(&buf[..header_end])
+        .map_err(|_| HttpError::Malformed("headers are not valid UTF-8"))?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m.to_string(), t, v.to_string()),
+        _ => return Err(HttpError::Malformed("bad request line")),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::Malformed("unsupported HTTP version"));
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p.to_string(), q.to_string()),
+        None => (target.to_string(), String::new()),
+    };
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(HttpError::Malformed("header line without colon"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        query,
+        version,
+        headers,
+        body: Vec::new(),
+    };
+
+    // whatever arrived past the headers is the start of the body
+    buf.drain(..header_end + 4);
+
+    // anything a proxy in front might frame differently is refused rather
+    // than guessed at (RFC 9112 §6.3): the body length has to be unambiguous
+    let transfer_encoding = single_header(&request, "transfer-encoding")?;
+    let content_length = single_header(&request, "content-length")?;
+    if transfer_encoding.is_some() && content_length.is_some() {
+        return Err(HttpError::Malformed("both Transfer-Encoding and Content-Length"));
+    }
+
+    if let Some(encoding) = transfer_encoding {
+        let last = encoding.rsplit(',').next().unwrap_or("").trim();
+        if !last.eq_ignore_ascii_case("chunked") {
+            return Err(HttpError::Malformed("unsupported Transfer-Encoding"));
+        }
+        request.body = read_chunked_body(stream, buf, limits).await?;
+    } else if let Some(len) = content_length {
+        // `usize::from_str` would take `+5` too
+        if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(HttpError::Malformed("invalid Content-Length"));
+        }
+        let len: usize = len
+            .parse()
+            .map_err(|_| HttpError::Malformed("invalid Content-Length"))?;
+        if len > limits.max_body_bytes {
+            return Err(HttpError::PayloadTooLarge);
+        }
+        while buf.len() < len {
+            if read_more(stream, buf).await? == 0 {
+                return Err(HttpError::Malformed("connection closed mid-body"));
+            }
+        }
+        request.body = buf.drain(..len).collect();
+    }
+
+    Ok(Some(request))
+}
+
+/// The value of a header that may appear at most once
+fn single_header<'r>(request: &'r Request, name: &'static str) -> Result<Option<&'r str>, HttpError> {
+    let mut values = request
+        .headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str());
+    let first = values.next();
+    if values.next().is_some() {
+        return Err(HttpError::Malformed("repeated framing header"));
+    }
+    Ok(first)
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, `buf` holds bytes already read
+async fn read_chunked_body<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<Vec<u8>, HttpError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line(stream, buf, limits).await?;
+        // chunk extensions after ';' are ignored
+        let size_str = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|_| HttpError::Malformed("invalid chunk size"))?;
+
+        if size == 0 {
+            // skip optional trailers up to the terminating blank line
+            while !read_line(stream, buf, limits).await?.is_empty() {}
+            return Ok(body);
+        }
+
+        // compared without adding: the size is client-chosen and may be near usize::MAX
+        if size > limits.max_body_bytes - body.len() {
+            return Err(HttpError::PayloadTooLarge);
+        }
+
+        while buf.len() < size + 2 {
+            if read_more(stream, buf).await? == 0 {
+                return Err(HttpError::Malformed("connection closed mid-chunk"));
+            }
+        }
+        if &buf[size..size + 2] != b"\r\n" {
+            return Err(HttpError::Malformed("chunk not terminated by CRLF"));
+        }
+        body.extend_from_slice(&buf[..size]);
+        buf.drain(..size + 2);
+    }
+}
+
+/// Pops one CRLF-terminated line off the front of `buf`, reading more as needed
+async fn read_line<S>(stream: &mut S, buf: &mut Vec<u8>, limits: &Limits) -> Result<String, HttpError>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(pos) = find_subslice(buf, b"\r\n") {
+            let line = String::from_utf8_lossy(&buf[..pos]).into_owned();
+            buf.drain(..pos + 2);
+            return Ok(line);
+        }
+        if buf.len() > limits.max_header_bytes {
+            return Err(HttpError::HeadersTooLarge);
+        }
+        if read_more(stream, buf).await? == 0 {
+            return Err(HttpError::Malformed("connection closed mid-line"));
+        }
+    }
+}
+
+async fn read_more<S>(stream: &mut S, buf: &mut Vec<u8>) -> Result<usize, HttpError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 4096];
+    let n = stream.read(&mut chunk).await?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    /// Parses `raw` fed through a pipe that hands out at most `piece` bytes
+    /// per read, so requests arrive split at arbitrary points
+    async fn parse_all(raw: &'static [u8], piece: usize, limits: Limits) -> Vec<Result<Request, HttpError>> {
+        let (mut client, mut server) = tokio::io::duplex(piece);
+        tokio::spawn(async move {
+            client.write_all(raw).await.unwrap();
+        });
+        let mut buf = Vec::new();
+        let mut parsed = Vec::new();
+        loop {
+            match read_request(&mut server, &mut buf, &limits).await {
+                Ok(Some(request)) => parsed.push(Ok(request)),
+                Ok(None) => break,
+                Err(e) => {
+                    parsed.push(Err(e));
+                    break;
+                }
+            }
+        }
+        parsed
+    }
+
+    #[tokio::test]
+    async fn content_length_body() {
+        let parsed = parse_all(b"POST /a?x=1 HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", 4096, Limits::default()).await;
+        let request = parsed[0].as_ref().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/a");
+        assert_eq!(request.query, "x=1");
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn chunked_body_split_across_reads() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: y\r\n\r\n";
+        for piece in [1, 3, 7, 4096] {
+            let parsed = parse_all(raw, piece, Limits::default()).await;
+            assert_eq!(parsed.len(), 1);
+            assert_eq!(parsed[0].as_ref().unwrap().body, b"hello world", "piece size {}", piece);
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests() {
+        let raw = b"POST /one HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+            POST /two HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi\
+            GET /three HTTP/1.1\r\n\r\n";
+        for piece in [2, 4096] {
+            let parsed = parse_all(raw, piece, Limits::default()).await;
+            let requests: Vec<&Request> = parsed.iter().map(|r| r.as_ref().unwrap()).collect();
+            assert_eq!(requests.len(), 3);
+            assert_eq!((requests[0].path.as_str(), requests[0].body.as_slice()), ("/one", &b"abc"[..]));
+            assert_eq!((requests[1].path.as_str(), requests[1].body.as_slice()), ("/two", &b"hi"[..]));
+            assert_eq!((requests[2].path.as_str(), requests[2].body.as_slice()), ("/three", &b""[..]));
+        }
+    }
+
+    #[tokio::test]
+    async fn huge_chunk_size_is_rejected_not_overflowed() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n";
+        let parsed = parse_all(raw, 4096, Limits::default()).await;
+        assert!(matches!(parsed[0], Err(HttpError::PayloadTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn chunks_over_the_body_limit_are_rejected() {
+        let limits = Limits {
+            max_header_bytes: 1024,
+            max_body_bytes: 8,
+        };
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n4\r\nabcd\r\n0\r\n\r\n";
+        let parsed = parse_all(raw, 4096, limits).await;
+        assert!(matches!(parsed[0], Err(HttpError::PayloadTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn oversized_content_length_and_headers() {
+        let limits = Limits {
+            max_header_bytes: 64,
+            max_body_bytes: 8,
+        };
+        let parsed = parse_all(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789", 4096, limits).await;
+        assert!(matches!(parsed[0], Err(HttpError::PayloadTooLarge)));
+
+        let raw = b"GET / HTTP/1.1\r\nX-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n";
+        let parsed = parse_all(raw, 4096, limits).await;
+        assert!(matches!(parsed[0], Err(HttpError::HeadersTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn malformed_requests() {
+        let parsed = parse_all(b"GET /\r\n\r\n", 4096, Limits::default()).await;
+        assert!(matches!(parsed[0], Err(HttpError::Malformed(_))));
+        let parsed = parse_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n", 4096, Limits::default()).await;
+        assert!(matches!(parsed[0], Err(HttpError::Malformed("invalid chunk size"))));
+        let parsed = parse_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort", 4096, Limits::default()).await;
+        assert!(matches!(parsed[0], Err(HttpError::Malformed("connection closed mid-body"))));
+    }
+
+    async fn first_error(raw: &'static [u8]) -> &'static str {
+        match parse_all(raw, 4096, Limits::default()).await.remove(0) {
+            Err(HttpError::Malformed(reason)) => reason,
+            Err(e) => panic!("expected Malformed, got {}", e),
+            Ok(_) => panic!("accepted {:?}", String::from_utf8_lossy(raw)),
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_content_length_is_rejected() {
+        let reason = first_error(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello").await;
+        assert_eq!(reason, "repeated framing header");
+        let reason = first_error(b"POST / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 6\r\n\r\nhello!").await;
+        assert_eq!(reason, "repeated framing header");
+        let reason = first_error(b"POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\nhello").await;
+        assert_eq!(reason, "invalid Content-Length");
+    }
+
+    #[tokio::test]
+    async fn content_length_with_transfer_encoding_is_rejected() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+        assert_eq!(first_error(raw).await, "both Transfer-Encoding and Content-Length");
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(first_error(raw).await, "repeated framing header");
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\nabc";
+        assert_eq!(first_error(raw).await, "unsupported Transfer-Encoding");
+    }
+
+    #[tokio::test]
+    async fn content_length_must_be_plain_digits() {
+        for raw in [
+            &b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 0x5\r\n\r\nhello",
+            b"POST / HTTP/1.1\r\nContent-Length: \r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+        ] {
+            assert_eq!(first_error(raw).await, "invalid Content-Length");
+        }
+        let parsed = parse_all(b"POST / HTTP/1.1\r\nContent-Length: 005\r\n\r\nhello", 4096, Limits::default()).await;
+        assert_eq!(parsed[0].as_ref().unwrap().body, b"hello");
+    }
+}
@@ -0,0 +1,377 @@
+use sqlx::SqlitePool;
+
+use crate::db::{parse_loan_date, sync_book_availability};
+use crate::error::{ApiError, ApiResult};
+use crate::http::Conn;
+use crate::models::*;
+use crate::router::{BoxFuture, Ctx, Router};
+use crate::{send_html, send_json};
+
+pub fn routes(router: &mut Router) {
+    router
+        .role("lender")
+        .get("/lender/api/books", books)
+        .get("/lender/api/search", search)
+        .get("/lender/api/myloans", myloans)
+        .post("/lender/api/checkout/{bookid}", checkout)
+        .post("/lender/api/return/{loanid}", return_loan)
+        .get("/lender/api/overdue", overdue);
+}
+
+//----------------------------------------------------------------------------------------------------------
+// routes — all gated on lender session by the router
+
+fn books(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(handle_lender_books(ctx.stream, ctx.pool))
+}
+
+fn search(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        match ctx.request.query_param("q") {
+            Some(q) => handle_lender_search(ctx.stream, ctx.pool, &q).await,
+            None => Ok(send_json(ctx.stream, b"[]").await?),
+        }
+    })
+}
+
+fn myloans(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        handle_lender_myloans(ctx.stream, ctx.pool, &username).await
+    })
+}
+
+fn checkout(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        match ctx.params.get::<i64>("bookid") {
+            Some(bookid) => {
+                let period_days = ctx.config.loans.period_days;
+                handle_lender_checkout(ctx.stream, ctx.pool, &username, bookid, period_days).await
+            }
+            None => Err(ApiError::Validation("bookid must be a number".to_string())),
+        }
+    })
+}
+
+fn return_loan(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        match ctx.params.get::<i64>("loanid") {
+            Some(loanid) => handle_lender_return(ctx.stream, ctx.pool, &username, loanid).await,
+            None => Err(ApiError::Validation("loanid must be a number".to_string())),
+        }
+    })
+}
+
+fn overdue(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        let fine_per_day = ctx.config.loans.fine_per_day;
+        handle_lender_overdue(ctx.stream, ctx.pool, &username, fine_per_day).await
+    })
+}
+
+//----------------------------------------------------------------------------------------------------------
+// lender dashboard fns
+
+async fn handle_lender_books(
+    stream: &mut Conn,
+    pool: SqlitePool,
+) -> ApiResult {
+    // Sync availability before showing books to lenders
+    sync_book_availability(&pool).await?;
+
+    let books = sqlx::query_as::<_, LenderBook>(
+        "
+        SELECT 
+            bookid,
+            title,
+            author,
+            genre,
+            available_copies
+        FROM books
+        WHERE available_copies > 0
+        "
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let json = serde_json::to_vec(&books)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+async fn handle_lender_search(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    query: &str,
+) -> ApiResult {
+    // Sync availability before searching
+    sync_book_availability(&pool).await?;
+
+    let q = format!("%{}%", query);
+
+    let books = sqlx::query_as::<_, LenderBook>(
+        "
+        SELECT
+            bookid,
+            title,
+            author,
+            isbn,
+            genre,
+            available_copies
+        FROM books
+        WHERE available_copies > 0
+          AND (
+            title LIKE ?
+            OR author LIKE ?
+            OR isbn LIKE ?
+            OR genre LIKE ?
+          )
+        "
+    )
+    .bind(&q)
+    .bind(&q)
+    .bind(&q)
+    .bind(&q)
+    .fetch_all(&pool)
+    .await?;
+
+    let json = serde_json::to_vec(&books)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+
+async fn handle_lender_myloans(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    username: &str,
+) -> ApiResult {
+
+    let loans = sqlx::query_as::<_, (i64, String, String, String)>(
+        "
+        SELECT 
+            l.loanid,
+            b.title,
+            l.checkout_date,
+            l.due_date
+        FROM loans l
+        JOIN users u ON u.id = l.loaned_to_user_id
+        JOIN books b ON b.bookid = l.loaned_bookid
+        WHERE u.username = ?
+          AND l.return_date IS NULL
+          AND date(l.due_date) >= date('now')
+        "
+    )
+    .bind(username)
+    .fetch_all(&pool)
+    .await?;
+
+    let result: Vec<LenderLoan> = loans
+        .into_iter()
+        .map(|(loanid, title, checkout_date, due_date)| {
+            LenderLoan {
+                loanid,
+                title,
+                checkout_date,
+                due_date,
+                status: "Borrowed".to_string(),
+            }
+        })
+        .collect();
+
+    let json = serde_json::to_vec(&result)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+
+async fn handle_lender_checkout(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    username: &str,
+    bookid: i64,
+    period_days: i64,
+) -> ApiResult {
+    let mut tx = pool.begin().await?;
+
+    // Get user id
+    let user = sqlx::query_as::<_, (i64,)>(
+        "SELECT id FROM users WHERE username = ?"
+    )
+    .bind(username)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user_id = match user {
+        Some((id,)) => id,
+        None => return Err(ApiError::NotFound("user not found".to_string())),
+    };
+
+
+    // Check availability
+    let row = sqlx::query_as::<_, (i64,)>(
+        "SELECT available_copies FROM books WHERE bookid = ?"
+    )
+    .bind(bookid)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let available = match row {
+        Some((v,)) => v,
+        None => return Err(ApiError::NotFound(format!("book {} not found", bookid))),
+    };
+
+
+    if available <= 0 {
+        return Err(ApiError::Conflict("Book not available".to_string()));
+    }
+
+    let already_borrowed = sqlx::query_scalar::<_, i64>(
+        "
+        SELECT COUNT(*)
+        FROM loans
+        WHERE loaned_to_user_id = ?
+        AND loaned_bookid = ?
+        AND return_date IS NULL
+        "
+    )
+    .bind(user_id)
+    .bind(bookid)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if already_borrowed > 0 {
+        return Err(ApiError::Conflict("You already borrowed this book".to_string()));
+    }
+
+    // Insert loan
+    sqlx::query(
+        "
+        INSERT INTO loans (loaned_to_user_id, loaned_bookid, checkout_date, due_date, return_date)
+        VALUES (?, ?, date('now'), date('now', ?), NULL)
+        "
+    )
+    .bind(user_id)
+    .bind(bookid)
+    .bind(format!("+{} days", period_days))
+    .execute(&mut *tx)
+    .await?;
+
+    // Decrement copies
+    sqlx::query(
+        "
+        UPDATE books
+        SET available_copies = available_copies - 1
+        WHERE bookid = ?
+        "
+    )
+    .bind(bookid)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(send_html(stream, b"<h1>Checkout successful</h1>").await?)
+}
+
+async fn handle_lender_return(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    username: &str,
+    loanid: i64,
+) -> ApiResult {
+    let mut tx = pool.begin().await?;
+
+    // Get bookid, borrower and whether it's already back
+    let (bookid, borrower, return_date): (i64, String, Option<String>) = sqlx::query_as(
+        "
+        SELECT l.loaned_bookid, u.username, l.return_date
+        FROM loans l
+        JOIN users u ON u.id = l.loaned_to_user_id
+        WHERE l.loanid = ?
+        "
+    )
+    .bind(loanid)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(format!("loan {} not found", loanid)))?;
+
+    if borrower != username {
+        return Err(ApiError::Forbidden("loan belongs to another user".to_string()));
+    }
+    if return_date.is_some() {
+        return Err(ApiError::Conflict("loan already returned".to_string()));
+    }
+
+    // Update loan
+    sqlx::query(
+        "UPDATE loans SET return_date = date('now') WHERE loanid = ?"
+    )
+    .bind(loanid)
+    .execute(&mut *tx)
+    .await?;
+
+    // Increment copies
+    sqlx::query(
+        "
+        UPDATE books
+        SET available_copies = available_copies + 1
+        WHERE bookid = ?
+        "
+    )
+    .bind(bookid)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(send_html(stream, b"<h1>Return successful</h1>").await?)
+}
+
+async fn handle_lender_overdue(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    username: &str,
+    fine_per_day: i64,
+) -> ApiResult {
+    let rows = sqlx::query_as::<_, (i64, String, String)>(
+        "
+        SELECT 
+            l.loanid,
+            b.title,
+            l.due_date
+        FROM loans l
+        JOIN users u ON u.id = l.loaned_to_user_id
+        JOIN books b ON b.bookid = l.loaned_bookid
+        WHERE u.username = ?
+          AND l.return_date IS NULL
+          AND date(l.due_date) < date('now')
+        "
+    )
+    .bind(username)
+    .fetch_all(&pool)
+    .await?;
+
+    let today = chrono::Utc::now().date_naive();
+
+    let result = rows
+        .into_iter()
+        .map(|(loanid, title, due_date)| {
+            let due = parse_loan_date(&due_date)?;
+            let days_overdue = (today - due).num_days();
+
+            Ok(OverdueLoan {
+                loanid,
+                title,
+                due_date,
+                days_overdue,
+                fine: days_overdue * fine_per_day,
+            })
+        })
+        .collect::<anyhow::Result<Vec<OverdueLoan>>>()?;
+
+    let json = serde_json::to_vec(&result)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+
@@ -0,0 +1,129 @@
+use std::borrow::Cow;
+use std::io::IsTerminal;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogConfig, LogFormat};
+
+/// Stands in for values that must not reach the log
+pub const REDACTED: &str = "[redacted]";
+
+/// Header names, query parameters and form fields (lower case) whose values
+/// are credentials: passwords, cookies and every kind of token or code
+const SENSITIVE: &[&str] = &[
+    "authorization",
+    "cookie",
+    "set-cookie",
+    "x-csrf-token",
+    "password",
+    "current_password",
+    "new_password",
+    "token",
+    "invite",
+    "code",
+    "secret",
+];
+
+/// Installs the global subscriber: human-readable lines, or one JSON object
+/// per event for log shippers. Spans are included, so every event logged
+/// while a request is handled carries its id, method, route and user.
+pub fn init(config: &LogConfig) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(&config.level)
+        .map_err(|e| anyhow::anyhow!("invalid log.level {:?}: {}", config.level, e))?;
+    // colours only for a terminal, not for files and pipes
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_ansi(std::io::stdout().is_terminal());
+
+    let installed = match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .try_init(),
+    };
+    installed.map_err(|e| anyhow::anyhow!("cannot install logger: {}", e))
+}
+
+/// Short random id that ties together the log lines of one request and is
+/// echoed to the client in `X-Request-Id`
+pub fn request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+pub fn is_sensitive(name: &str) -> bool {
+    SENSITIVE.iter().any(|s| s.eq_ignore_ascii_case(name))
+}
+
+/// `a=1&token=abc` -> `a=1&token=[redacted]`; borrowed when nothing matched
+pub fn redact_query(query: &str) -> Cow<'_, str> {
+    let secret = |pair: &str| pair.split_once('=').is_some_and(|(key, _)| is_sensitive(key));
+    if !query.split('&').any(secret) {
+        return Cow::Borrowed(query);
+    }
+    let pairs: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if is_sensitive(key) => format!("{}={}", key, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect();
+    Cow::Owned(pairs.join("&"))
+}
+
+/// Free text such as a mail body, with the query of every link in it passed
+/// through [`redact_query`]: `…/reset.html?token=abc` -> `…/reset.html?token=[redacted]`
+pub fn redact_links(text: &str) -> Cow<'_, str> {
+    if !text.contains('?') {
+        return Cow::Borrowed(text);
+    }
+    let words: Vec<Cow<'_, str>> = text
+        .split_inclusive(char::is_whitespace)
+        .map(|word| match word.split_once('?') {
+            Some((before, query)) => {
+                let end = query.trim_end();
+                let (query, trailing) = query.split_at(end.len());
+                Cow::Owned(format!("{}?{}{}", before, redact_query(query), trailing))
+            }
+            None => Cow::Borrowed(word),
+        })
+        .collect();
+    Cow::Owned(words.concat())
+}
+
+/// Request headers for debug output, with credential values blanked
+pub fn redact_headers(headers: &[(String, String)]) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_sensitive(name) { REDACTED } else { value.as_str() };
+            (name.as_str(), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_lose_credential_values_only() {
+        assert_eq!(redact_query("page=2&q=rust"), "page=2&q=rust");
+        assert!(matches!(redact_query("page=2"), Cow::Borrowed(_)));
+        assert_eq!(redact_query("a=1&Token=abc&b"), "a=1&Token=[redacted]&b");
+        assert_eq!(redact_query("invite=x&code=123456"), "invite=[redacted]&code=[redacted]");
+    }
+
+    #[test]
+    fn links_in_text_lose_their_tokens() {
+        let body = "Hello,\n\nopen http://lib.example/reset.html?token=abc123 within 30 minutes.\n\nIs this right?\n";
+        assert_eq!(
+            redact_links(body),
+            "Hello,\n\nopen http://lib.example/reset.html?token=[redacted] within 30 minutes.\n\nIs this right?\n"
+        );
+        assert_eq!(redact_links("end with a link: /x?token=abc"), "end with a link: /x?token=[redacted]");
+        assert!(matches!(redact_links("no links here"), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,267 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::MailConfig;
+use crate::logging;
+
+/// Longest a whole SMTP conversation may take; a relay that stops answering
+/// must not pin the task sending the mail forever
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A plain-text message
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Something that can deliver mail
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> anyhow::Result<()>;
+}
+
+/// Picks the SMTP mailer when a relay is configured, else logs to stdout
+pub fn from_config(config: &MailConfig) -> Arc<dyn Mailer> {
+    match &config.smtp_addr {
+        Some(addr) => Arc::new(SmtpMailer {
+            addr: addr.clone(),
+            from: config.from.clone(),
+            timeout: SMTP_TIMEOUT,
+        }),
+        None => Arc::new(LogMailer),
+    }
+}
+
+/// Fallback when no relay is configured: writes the message to the log
+/// instead of sending it. Tokens in links are redacted, since the log may be
+/// shipped elsewhere; to follow a reset link locally, run `fake-smtp`.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> anyhow::Result<()> {
+        let body = logging::redact_links(&email.body);
+        tracing::warn!(to = %email.to, subject = %email.subject, body = %body, "No SMTP relay configured, mail logged instead");
+        Ok(())
+    }
+}
+
+/// Minimal SMTP client for a trusted relay (no TLS, no AUTH), e.g. a local
+/// postfix/sendmail or `library fake-smtp`
+pub struct SmtpMailer {
+    addr: String,
+    from: String,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> anyhow::Result<()> {
+        tokio::time::timeout(self.timeout, self.deliver(email))
+            .await
+            .map_err(|_| anyhow::anyhow!("SMTP relay {} did not answer within {:?}", self.addr, self.timeout))?
+    }
+}
+
+impl SmtpMailer {
+    async fn deliver(&self, email: &Email) -> anyhow::Result<()> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+
+        expect_reply(&mut read, 220).await?;
+        command(&mut write, &mut read, "HELO localhost", 250).await?;
+        command(&mut write, &mut read, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        command(&mut write, &mut read, &format!("RCPT TO:<{}>", email.to), 250).await?;
+        command(&mut write, &mut read, "DATA", 354).await?;
+
+        let mut message = format!(
+            "From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+            self.from, email.to, email.subject
+        );
+        for line in email.body.lines() {
+            // dot-stuffing: a leading '.' would otherwise end the message early
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push_str(".\r\n");
+        write.write_all(message.as_bytes()).await?;
+        expect_reply(&mut read, 250).await?;
+
+        command(&mut write, &mut read, "QUIT", 221).await?;
+        Ok(())
+    }
+}
+
+async fn command<R, W>(write: &mut W, read: &mut R, line: &str, expected: u16) -> anyhow::Result<()>
+where
+    R: AsyncBufReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    write.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    expect_reply(read, expected).await
+}
+
+/// Reads a (possibly multi-line) reply and checks its code
+async fn expect_reply<R>(read: &mut R, expected: u16) -> anyhow::Result<()>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    loop {
+        let mut line = String::new();
+        if read.read_line(&mut line).await? == 0 {
+            anyhow::bail!("SMTP server closed the connection");
+        }
+        let code: u16 = line
+            .get(..3)
+            .and_then(|c| c.parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("malformed SMTP reply {:?}", line.trim_end()))?;
+        // "250-..." continues, "250 ..." is the last line
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if code != expected {
+            anyhow::bail!("SMTP server replied {:?}, expected {}", line.trim_end(), expected);
+        }
+        return Ok(());
+    }
+}
+
+/// Local SMTP stand-in for development: accepts every message and prints it
+pub async fn run_fake_smtp(addr: &str) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("Fake SMTP server listening on {}", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let print = |message: String| {
+                println!("---- fake SMTP message ----");
+                print!("{}", message);
+                println!("---------------------------");
+            };
+            if let Err(e) = fake_smtp_session(stream, print).await {
+                eprintln!("Fake SMTP session error: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Answers one client, handing each message (headers and body, dot-stuffing
+/// undone, `\n` line ends) to `received`
+async fn fake_smtp_session(stream: TcpStream, mut received: impl FnMut(String)) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    write.write_all(b"220 localhost fake SMTP ready\r\n").await?;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if read.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let verb = line.trim_end().to_ascii_uppercase();
+
+        if verb == "DATA" {
+            write.write_all(b"354 end data with <CR><LF>.<CR><LF>\r\n").await?;
+            let mut message = String::new();
+            loop {
+                line.clear();
+                if read.read_line(&mut line).await? == 0 {
+                    return Ok(());
+                }
+                let text = line.trim_end_matches(['\r', '\n']);
+                if text == "." {
+                    break;
+                }
+                message.push_str(text.strip_prefix('.').unwrap_or(text));
+                message.push('\n');
+            }
+            received(message);
+            write.write_all(b"250 OK queued\r\n").await?;
+        } else if verb == "QUIT" {
+            write.write_all(b"221 bye\r\n").await?;
+            return Ok(());
+        } else {
+            // HELO/EHLO, MAIL FROM, RCPT TO, RSET, NOOP... all accepted
+            write.write_all(b"250 OK\r\n").await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fake SMTP server on an ephemeral port; messages come out of the receiver
+    async fn fake_relay() -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            fake_smtp_session(stream, move |message| tx.send(message).unwrap())
+                .await
+                .unwrap();
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn message_with_leading_dots_arrives_intact() {
+        let (addr, mut received) = fake_relay().await;
+        let mailer = SmtpMailer {
+            addr,
+            from: "library@localhost".to_string(),
+            timeout: SMTP_TIMEOUT,
+        };
+        let body = "Hi,\n.hidden line\n..two dots\n.\nlast line\n";
+        mailer
+            .send(&Email {
+                to: "alice@example.com".to_string(),
+                subject: "Test".to_string(),
+                body: body.to_string(),
+            })
+            .await
+            .unwrap();
+
+        let message = received.recv().await.unwrap();
+        let (headers, received_body) = message.split_once("\n\n").unwrap();
+        assert!(headers.contains("To: <alice@example.com>"), "{}", headers);
+        assert!(headers.contains("Subject: Test"), "{}", headers);
+        assert_eq!(received_body, body);
+    }
+
+    #[tokio::test]
+    async fn silent_relay_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // accepts, then never says a word
+        let _relay = tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let mailer = SmtpMailer {
+            addr,
+            from: "library@localhost".to_string(),
+            timeout: Duration::from_millis(200),
+        };
+        let email = Email {
+            to: "alice@example.com".to_string(),
+            subject: "Test".to_string(),
+            body: "Hi".to_string(),
+        };
+        let error = mailer.send(&email).await.unwrap_err();
+        assert!(error.to_string().contains("did not answer"), "{}", error);
+    }
+}
@@ -3,241 +3,611 @@ mod db;
 
 mod models;
 mod auth;
+mod http;
+mod response;
+mod static_files;
+mod tls;
+mod config;
+mod throttle;
+mod router;
+mod error;
+mod admin;
+mod lender;
+mod account;
+mod mailer;
+mod session;
+mod totp;
+mod two_factor;
+mod logging;
+mod metrics;
+mod health;
+mod migrate;
 use db::get_db_pool;
 use sqlx::SqlitePool;
 
 use std::collections::HashMap;
-use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use auth::{hash_password, verify_password, generate_session_token};
-use models::*;
-
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use auth::{hash_password, hash_token, verify_password, verify_dummy_password, normalize_email, PasswordPolicy};
+use http::{Conn, HttpError, Request};
+use response::Response;
+use tokio_rustls::TlsAcceptor;
+use router::{Access, BoxFuture, Ctx, Resolved, Router};
+use error::{ApiError, ApiResult};
+use config::{Command, Config};
+use throttle::Throttle;
+use mailer::Mailer;
+use session::SessionStore;
+use metrics::{LoginResult, Metrics};
+use tracing::{debug, error, info, warn, Instrument};
+
+/// Pause after a failed accept, so running out of file descriptors doesn't
+/// turn into a busy loop
+const ACCEPT_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
 
 //----------------------------------------------------------------------------------------------------------
 // creating server to run on
 
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    println!("Starting async server...");
+    let (config, command) = Config::load()?;
+    logging::init(&config.log)?;
+    let passwords = PasswordPolicy::load(&config.password)?;
+
+    match command {
+        Command::Serve => {}
+        Command::CreateAdmin { username } => {
+            let pool = get_db_pool(&config.database).await?;
+            migrate::on_startup(&pool, config.database.auto_migrate).await?;
+            let result = create_admin(&pool, &passwords, &username).await;
+            pool.close().await;
+            return result;
+        }
+        Command::Migrate(action) => {
+            let pool = get_db_pool(&config.database).await?;
+            let result = migrate::command(&pool, action).await;
+            pool.close().await;
+            return result;
+        }
+        Command::FakeSmtp { addr } => return mailer::run_fake_smtp(&addr).await,
+    }
+
+    info!("Starting async server...");
+
+    let pool = get_db_pool(&config.database).await?;
+    migrate::on_startup(&pool, config.database.auto_migrate).await?;
+
+    info!("Database ready.");
+
+    let sessions = SessionStore::load(&config.session)?;
+
+    let state = Arc::new(AppState {
+        pool,
+        sessions,
+        router: build_router(),
+        throttle: Throttle::new(
+            config.login.throttle_free_attempts,
+            config.login.throttle_max_backoff(),
+        ),
+        passwords,
+        mailer: mailer::from_config(&config.mail),
+        metrics: Metrics::new(),
+        config,
+    });
+    let config = &state.config;
+
+    // TLS is optional: enabled when a cert/key pair is configured
+    let acceptor = match &config.tls {
+        Some(settings) => Some(tls::load_acceptor(settings)?),
+        None => None,
+    };
+
+    let bind_addr = &config.server.bind_addr;
+    let listener = TcpListener::bind(bind_addr).await?;
+    let scheme = if acceptor.is_some() { "https" } else { "http" };
+    info!("Listening on {}://{}", scheme, bind_addr);
+
+    // a permit per live connection, on either listener; when they run out we
+    // stop accepting
+    let limiter = Arc::new(Semaphore::new(config.server.max_connections));
+
+    let redirect = config.tls.as_ref().and_then(|t| t.redirect_addr.clone());
+    let redirect_task = match redirect {
+        Some(redirect_addr) => {
+            let https_port = listener.local_addr()?.port();
+            let limits = config.server.limits();
+            let timeouts = config.server.timeouts();
+            let limiter = limiter.clone();
+            Some(tokio::spawn(async move {
+                let redirect = tls::run_redirect_listener(redirect_addr, https_port, limits, timeouts, limiter);
+                if let Err(e) = redirect.await {
+                    error!(error = ?e, "Redirect listener failed");
+                }
+            }))
+        }
+        None => None,
+    };
+
+    // expired sessions are deleted in the background rather than on lookup
+    let sweeper = tokio::spawn(session::run_sweeper(state.pool.clone(), config.session.sweep_interval()));
+    // and throttle entries that have served their time, on a timer too
+    let pruner = tokio::spawn({
+        let state = state.clone();
+        async move { state.throttle.run_pruner().await }
+    });
+
+    let served = serve(listener, acceptor, state.clone(), limiter, shutdown_signal()).await;
+
+    if let Some(task) = redirect_task {
+        task.abort();
+    }
+    sweeper.abort();
+    pruner.abort();
+    state.pool.close().await;
+    info!("Database closed.");
 
-    let pool = get_db_pool().await;
-    
-    println!("Database ready.");
+    served
+}
+
+/// Accepts connections until `shutdown` resolves, then stops accepting and
+/// gives in-flight requests the grace period to finish
+async fn serve(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    state: Arc<AppState>,
+    limiter: Arc<Semaphore>,
+    shutdown: impl std::future::Future<Output = std::io::Result<()>>,
+) -> anyhow::Result<()> {
+    let config = &state.config;
+
+    // flipped to true on shutdown so idle keep-alive connections close early
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut connections = JoinSet::new();
 
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    println!("Listening on http://127.0.0.1:8080");
+    tokio::pin!(shutdown);
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let pool = pool.clone();
+        tokio::select! {
+            accepted = accept_with_permit(&listener, &limiter) => {
+                let (stream, permit) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // usually out of file descriptors; the permit is back
+                        // in the pool, so wait for connections to close
+                        error!(error = ?e, "Accept failed");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let state = state.clone();
+                let acceptor = acceptor.clone();
+                let shutdown = shutdown_rx.clone();
+
+                connections.spawn(async move {
+                    if let Err(e) = handle_connection(stream, acceptor, state, shutdown).await {
+                        warn!(error = ?e, "Connection error");
+                    }
+                    drop(permit);
+                });
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, pool).await {
-                eprintln!("Connection error: {:?}", e);
+                // reap finished connections so the set only holds live ones
+                while connections.try_join_next().is_some() {}
             }
-        });
+            result = &mut shutdown => {
+                result?;
+                break;
+            }
+        }
     }
-}
 
-//----------------------------------------------------------------------------------------------------------
-// get/post from webpages sent back to server
+    drop(listener);
+    // connections that ended since the last accept aren't in flight
+    while connections.try_join_next().is_some() {}
+    let in_flight = connections.len();
+    info!("Shutting down, waiting for {} connection(s)...", in_flight);
+    let _ = shutdown_tx.send(true);
 
+    let deadline = tokio::time::sleep(config.server.shutdown_grace());
+    tokio::pin!(deadline);
+    let mut drained = 0;
+    loop {
+        tokio::select! {
+            next = connections.join_next() => match next {
+                Some(_) => drained += 1,
+                None => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
 
+    // whatever is left is cut off; open transactions roll back when dropped
+    let aborted = connections.len();
+    connections.shutdown().await;
+    info!("Drained {} connection(s), aborted {}.", drained, aborted);
 
+    Ok(())
+}
 
-async fn handle_connection(
-    mut stream: TcpStream,
-    pool: SqlitePool,
-) -> anyhow::Result<()> {
-    let mut buffer = [0u8; 16384];
-    let bytes_read = stream.read(&mut buffer).await?;
-    if bytes_read == 0 {
-        return Ok(());
+/// Bootstraps an admin account from the command line; later staff accounts
+/// are created through invitations
+async fn create_admin(pool: &SqlitePool, passwords: &PasswordPolicy, username: &str) -> anyhow::Result<()> {
+    let password = match std::env::var("LIBRARY_ADMIN_PASSWORD") {
+        Ok(password) => password,
+        Err(_) => {
+            eprintln!("Password for {}:", username);
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if username.trim().is_empty() {
+        anyhow::bail!("username must not be empty");
     }
+    passwords.check(username, &password).map_err(anyhow::Error::msg)?;
 
-    let request = String::from_utf8_lossy(&buffer[..bytes_read]);
-    println!("==== RAW REQUEST ====");
-    println!("{}", request);
+    let hashed = hash_password(&password).await?;
+    let inserted = sqlx::query("INSERT INTO users (username, password, role) VALUES (?, ?, 'admin')")
+        .bind(username)
+        .bind(&hashed)
+        .execute(pool)
+        .await;
+    match inserted {
+        Ok(_) => {
+            println!("Created admin account {}", username);
+            Ok(())
+        }
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            anyhow::bail!("user {} already exists", username)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
 
-    let (method, path) = parse_request_line(&request);
-    let body = extract_body(&request);
+/// Waits for a free connection slot, then for the next client
+async fn accept_with_permit(
+    listener: &TcpListener,
+    limiter: &Arc<Semaphore>,
+) -> anyhow::Result<(TcpStream, OwnedSemaphorePermit)> {
+    let permit = limiter.clone().acquire_owned().await?;
+    let (stream, _) = listener.accept().await?;
+    Ok((stream, permit))
+}
 
-    // Resolve session from cookie (DB-backed)
-    let session = resolve_session(&request, &pool).await;
+/// Resolves on Ctrl-C, or SIGTERM on unix
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
 
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
 
-    match (method.as_str(), path.as_str()) {
-        // serving login/register pages
-        ("GET", "/") => serve_file(&mut stream, "static/index.html").await?,
-        ("GET", "/register.html") => serve_file(&mut stream, "static/register.html").await?,
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
 
-        // forms
-        ("POST", "/login") => handle_login(&mut stream, pool, body).await?,
-        ("POST", "/register") => handle_register(&mut stream, pool, body).await?,
+//----------------------------------------------------------------------------------------------------------
+// get/post from webpages sent back to server
 
-        // logout — destroys session then redirects
-        ("GET", "/logout") | ("POST", "/logout") => {
-            destroy_session(&pool, &request).await;
-            send_logout_redirect(&mut stream).await?;
-        }
 
-        // protected pages — require valid session with correct role
-        ("GET", "/admin.html") => {
-            match &session {
-                Some((_, role)) if role == "admin" => {
-                    serve_file(&mut stream, "static/admin.html").await?
-                }
-                _ => send_redirect(&mut stream, "/").await?,
-            }
-        }
-        ("GET", "/lender.html") => {
-            match &session {
-                Some((_, role)) if role == "lender" => {
-                    serve_file(&mut stream, "static/lender.html").await?
-                }
-                _ => send_redirect(&mut stream, "/").await?,
+
+
+async fn handle_connection(
+    stream: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+    state: Arc<AppState>,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let config = &state.config;
+    let limits = config.server.limits();
+    let keep_alive = config.server.keep_alive();
+    let timeouts = config.server.timeouts();
+    let peer_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+
+    let mut conn = match acceptor {
+        Some(acceptor) => {
+            // a client that never finishes the handshake is dropped like an idle one
+            match tokio::time::timeout(keep_alive.idle_timeout, acceptor.accept(stream)).await {
+                Ok(tls_stream) => Conn::new(tls_stream?, true),
+                Err(_) => return Ok(()),
             }
         }
+        None => Conn::new(stream, false),
+    };
+    conn.write_timeout = timeouts.write;
+    conn.peer_ip = peer_ip;
+    let _open = state.metrics.connection();
+    let mut served = 0;
 
-        // admin dashboard api — all gated on admin session
-        ("GET", "/admin/api/users") => {
-            match &session {
-                Some((_, role)) if role == "admin" => {
-                    handle_admin_users(&mut stream, pool).await?
-                }
-                _ => send_json(&mut stream, b"{\"error\":\"unauthorized\"}").await?,
-            }
+    loop {
+        // an idle connection is dropped quietly once the timeout passes or on shutdown
+        tokio::select! {
+            started = tokio::time::timeout(keep_alive.idle_timeout, conn.wait_for_request()) => match started {
+                Ok(Ok(true)) => {}
+                Ok(Ok(false)) | Err(_) => return Ok(()),
+                Ok(Err(e)) => return Err(e.into()),
+            },
+            _ = shutdown.changed() => return Ok(()),
         }
-        ("GET", "/admin/api/books") => {
-            match &session {
-                Some((_, role)) if role == "admin" => {
-                    handle_admin_books(&mut stream, pool).await?
-                }
-                _ => send_json(&mut stream, b"{\"error\":\"unauthorized\"}").await?,
+
+        // once it has started, the whole request must arrive within the read timeout
+        let next = match tokio::time::timeout(timeouts.read, conn.next_request(&limits)).await {
+            Ok(next) => next,
+            Err(_) => {
+                warn!(peer = ?conn.peer_ip, "Request timed out before it was read in full");
+                conn.keep_alive = false;
+                send_response(&mut conn, 408, "text/html", b"<h1>Request timed out</h1>").await?;
+                return Ok(());
             }
-        }
-        ("GET", "/admin/api/loans") => {
-            match &session {
-                Some((_, role)) if role == "admin" => {
-                    handle_admin_loans(&mut stream, pool).await?
-                }
-                _ => send_json(&mut stream, b"{\"error\":\"unauthorized\"}").await?,
+        };
+
+        let request = match next {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(HttpError::Io(e)) => return Err(e.into()),
+            Err(e) => {
+                let status = match e {
+                    HttpError::HeadersTooLarge => 431,
+                    HttpError::PayloadTooLarge => 413,
+                    _ => 400,
+                };
+                warn!(peer = ?conn.peer_ip, status, "Rejected malformed request: {}", e);
+                conn.keep_alive = false;
+                let body = format!("<h1>{}</h1>", e);
+                send_response(&mut conn, status, "text/html", body.as_bytes()).await?;
+                return Ok(());
             }
+        };
+
+        served += 1;
+        conn.keep_alive = request.wants_keep_alive()
+            && served < keep_alive.max_requests
+            && !*shutdown.borrow();
+
+        let resolved = state.router.resolve(&request.method, &request.path);
+        let route = route_label(&resolved, &request);
+
+        // every event logged while handling the request carries these fields;
+        // the route is the matched pattern, not the raw path, which can carry
+        // credentials in path parameters
+        let request_id = logging::request_id();
+        let span = tracing::info_span!(
+            "request",
+            id = %request_id,
+            method = %request.method,
+            route = %route,
+            query = %logging::redact_query(&request.query),
+            user_id = tracing::field::Empty,
+        );
+        conn.request_id = Some(request_id);
+        conn.status = None;
+        conn.cookies.clear();
+        conn.head_only = request.method == "HEAD";
+        let started = Instant::now();
+
+        let result = handle_request(&mut conn, &state, &request, resolved)
+            .instrument(span.clone())
+            .await;
+
+        let elapsed = started.elapsed();
+        if let Some(status) = conn.status {
+            state.metrics.observe_request(&request.method, route, status, elapsed);
         }
-        ("GET", "/admin/api/overdue") => {
-            match &session {
-                Some((_, role)) if role == "admin" => {
-                    handle_admin_overdue(&mut stream, pool).await?
-                }
-                _ => send_json(&mut stream, b"{\"error\":\"unauthorized\"}").await?,
-            }
+        let latency_ms = elapsed.as_secs_f64() * 1000.0;
+        span.in_scope(|| match &result {
+            Ok(()) => info!(status = conn.status, latency_ms, "Request served"),
+            Err(e) => warn!(status = conn.status, latency_ms, error = ?e, "Request failed"),
+        });
+        conn.request_id = None;
+        result?;
+
+        if !conn.keep_alive {
+            return Ok(());
         }
-        ("POST", "/admin/api/books") => {
-            match &session {
-                Some((_, role)) if role == "admin" => {
-                    handle_admin_add_book(&mut stream, pool, body).await?
+    }
+}
+
+/// Metrics and log label for a request: the matched pattern, so `/books/1`
+/// and `/books/2` share one series and path parameters stay out of the log
+fn route_label(resolved: &Resolved<'_>, request: &Request) -> &'static str {
+    match resolved {
+        Resolved::Found(.., pattern) => pattern,
+        Resolved::MethodNotAllowed(_) => "(method not allowed)",
+        Resolved::NotFound if static_files::resolve_path(&request.path).is_some() => "(static)",
+        Resolved::NotFound => "(unmatched)",
+    }
+}
+
+async fn handle_request(
+    stream: &mut Conn,
+    state: &AppState,
+    request: &Request,
+    resolved: Resolved<'_>,
+) -> anyhow::Result<()> {
+    debug!(headers = ?logging::redact_headers(&request.headers), "Request received");
+
+    match resolved {
+        Resolved::Found(handler, params, access, _) => {
+            // Resolve session from cookie (DB-backed)
+            let mut user = match state.sessions.resolve(&state.pool, request).await {
+                Ok(user) => user,
+                // answering as if logged out would sign everyone out during
+                // a database outage
+                Err(e) => {
+                    error!(error = ?e, "Session lookup failed");
+                    let e = ApiError::Internal(e);
+                    return send_response(stream, e.status(), "application/json", &e.to_json()).await;
                 }
-                _ => send_json(&mut stream, b"{\"error\":\"unauthorized\"}").await?,
-            }
-        }
-        ("PUT", path) if path.starts_with("/admin/api/books") => {
-            match &session {
-                Some((_, role)) if role == "admin" => {
-                    if let Some(bookid) = parse_query_param(path, "bookid")
-                        .and_then(|v| v.parse::<i64>().ok())
-                    {
-                        handle_admin_update_book(&mut stream, pool, bookid, body).await?;
-                    } else {
-                        send_html(&mut stream, b"Missing bookid").await?;
-                    }
+            };
+            if let Some(user) = &mut user {
+                tracing::Span::current().record("user_id", user.id);
+                if let Some(refreshed) = user.refreshed.take() {
+                    stream.cookies.extend(refreshed.cookies(stream.is_secure()));
                 }
-                _ => { send_json(&mut stream, b"{\"error\":\"unauthorized\"}").await?; }
             }
-        }
-        ("DELETE", path) if path.starts_with("/admin/api/books") => {
-            match &session {
-                Some((_, role)) if role == "admin" => {
-                    if let Some(bookid) = parse_query_param(path, "bookid")
-                        .and_then(|v| v.parse::<i64>().ok())
-                    {
-                        handle_admin_delete_book(&mut stream, pool, bookid).await?;
-                    } else {
-                        send_html(&mut stream, b"Missing bookid").await?;
-                    }
+            let needs_two_factor = user
+                .as_ref()
+                .is_some_and(|u| two_factor::must_enroll(&state.config, &u.role, u.two_factor));
+            let session = user.map(|u| (u.username, u.role));
+
+            let result = match (access, &session) {
+                // guarded routes: no session -> 401, wrong role -> 403
+                (Access::Session | Access::Role(_), None) => {
+                    Err(ApiError::Unauthorized("login required".to_string()))
                 }
-                _ => { send_json(&mut stream, b"{\"error\":\"unauthorized\"}").await?; }
-            }
-        }
-
-        // lender dashboard api — gated on lender session
-        ("GET", "/lender/api/books") => {
-            match &session {
-                Some((_, role)) if role == "lender" => {
-                    handle_lender_books(&mut stream, pool).await?
+                (Access::Role(required), Some((_, actual))) if actual != required => {
+                    Err(ApiError::Forbidden("insufficient role".to_string()))
                 }
-                _ => send_json(&mut stream, b"[]").await?,
-            }
-        }
-        ("GET", path) if path.starts_with("/lender/api/search") => {
-            match &session {
-                Some((_, role)) if role == "lender" => {
-                    if let Some(q) = parse_query_param(path, "q") {
-                        handle_lender_search(&mut stream, pool, &q).await?;
-                    } else {
-                        send_json(&mut stream, b"[]").await?;
-                    }
+                // role routes stay closed until a mandatory authenticator is set up
+                (Access::Role(_), Some(_)) if needs_two_factor => Err(ApiError::Forbidden(
+                    "two-factor authentication must be set up first, see /account.html".to_string(),
+                )),
+                // the session cookie alone doesn't authorize a state change;
+                // the page must also echo the session's CSRF token
+                (Access::Session | Access::Role(_), Some(_))
+                    if !matches!(request.method.as_str(), "GET" | "HEAD")
+                        && !state.sessions.verify_csrf(request) =>
+                {
+                    Err(ApiError::Forbidden("missing or invalid CSRF token".to_string()))
                 }
-                _ => { send_json(&mut stream, b"[]").await?; }
-            }
-        }
-        ("GET", path) if path.starts_with("/lender/api/myloans") => {
-            match &session {
-                Some((username, role)) if role == "lender" => {
-                    handle_lender_myloans(&mut stream, pool, username).await?;
+                _ => {
+                    let ctx = Ctx {
+                        stream: &mut *stream,
+                        pool: state.pool.clone(),
+                        config: &state.config,
+                        throttle: &state.throttle,
+                        sessions: &state.sessions,
+                        passwords: &state.passwords,
+                        mailer: &state.mailer,
+                        metrics: &state.metrics,
+                        request,
+                        params,
+                        session,
+                    };
+                    handler(ctx).await
                 }
-                _ => { send_json(&mut stream, b"[]").await?; }
-            }
-        }
-        ("POST", path) if path.starts_with("/lender/api/checkout") => {
-            match &session {
-                Some((username, role)) if role == "lender" => {
-                    if let Some(bookid) = parse_query_param(path, "bookid").and_then(|v| v.parse().ok()) {
-                        handle_lender_checkout(&mut stream, pool, username, bookid).await?;
-                    } else {
-                        send_html(&mut stream, b"<h1>Invalid checkout request</h1>").await?;
+            };
+
+            match result {
+                Ok(()) => Ok(()),
+                Err(e) => {
+                    if let ApiError::Internal(inner) = &e {
+                        error!(error = ?inner, "Handler failed");
                     }
+                    send_response(stream, e.status(), "application/json", &e.to_json()).await
                 }
-                _ => { send_html(&mut stream, b"<h1>Not logged in</h1>").await?; }
             }
         }
-        ("POST", path) if path.starts_with("/lender/api/return") => {
-            match &session {
-                Some((_, role)) if role == "lender" => {
-                    if let Some(loanid) = parse_query_param(path, "loanid").and_then(|v| v.parse().ok()) {
-                        handle_lender_return(&mut stream, pool, loanid).await?;
-                    } else {
-                        send_html(&mut stream, b"<h1>Invalid return request</h1>").await?;
+        Resolved::MethodNotAllowed(allowed) => send_405(stream, &allowed).await,
+        Resolved::NotFound => {
+            // anything else under static/ (css, js, images...). The dashboard
+            // pages never get here: their exact paths are routed above and
+            // resolve_path rejects every other spelling of them.
+            if matches!(request.method.as_str(), "GET" | "HEAD") {
+                if let Some(file) = static_files::resolve_path(&request.path) {
+                    if static_files::serve(stream, request, &file).await? {
+                        return Ok(());
                     }
                 }
-                _ => { send_html(&mut stream, b"<h1>Not logged in</h1>").await?; }
             }
+            send_404(stream).await
         }
-        ("GET", path) if path.starts_with("/lender/api/overdue") => {
-            match &session {
-                Some((username, role)) if role == "lender" => {
-                    handle_lender_overdue(&mut stream, pool, username).await?;
-                }
-                _ => { send_json(&mut stream, b"[]").await?; }
-            }
-        }
-
-        _ => send_404(&mut stream).await?,
     }
+}
 
-    Ok(())
+/// Long-lived state shared by every connection
+struct AppState {
+    pool: SqlitePool,
+    sessions: SessionStore,
+    router: Router,
+    config: Config,
+    throttle: Throttle,
+    passwords: PasswordPolicy,
+    mailer: Arc<dyn Mailer>,
+    metrics: Metrics,
+}
+
+/// Builds the route table; dashboard modules register their own routes
+fn build_router() -> Router {
+    let mut router = Router::new();
+
+    router
+        // serving login/register pages
+        .get("/", index_page)
+        .get("/register.html", register_page)
+        // forms
+        .post("/login", login)
+        .post("/register", register)
+        // protected pages — require valid session with correct role
+        .get("/admin.html", admin_page)
+        .get("/lender.html", lender_page);
+
+    // logout — destroys session then redirects; POST only, so it is CSRF-checked
+    router.logged_in().post("/logout", logout);
+
+    admin::routes(&mut router);
+    account::routes(&mut router);
+    two_factor::routes(&mut router);
+    lender::routes(&mut router);
+    metrics::routes(&mut router);
+    health::routes(&mut router);
+
+    router
+}
+
+fn index_page(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move { Ok(serve_file(ctx.stream, ctx.request, "static/index.html").await?) })
+}
+
+fn register_page(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move { Ok(serve_file(ctx.stream, ctx.request, "static/register.html").await?) })
+}
+
+fn login(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let (throttle, sessions, metrics) = (ctx.throttle, ctx.sessions, ctx.metrics);
+        Ok(handle_login(ctx.stream, ctx.pool, ctx.config, throttle, sessions, metrics, ctx.request).await?) })
+}
+
+fn register(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        Ok(handle_register(ctx.stream, ctx.pool, ctx.throttle, ctx.sessions, ctx.passwords, ctx.request).await?) })
+}
+
+fn logout(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        ctx.sessions.destroy(&ctx.pool, ctx.request).await;
+        Ok(send_logout_redirect(ctx.stream).await?)
+    })
+}
+
+fn admin_page(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        if ctx.has_role("admin") {
+            Ok(serve_file(ctx.stream, ctx.request, "static/admin.html").await?)
+        } else {
+            Ok(send_redirect(ctx.stream, "/").await?)
+        }
+    })
+}
+
+fn lender_page(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        if ctx.has_role("lender") {
+            Ok(serve_file(ctx.stream, ctx.request, "static/lender.html").await?)
+        } else {
+            Ok(send_redirect(ctx.stream, "/").await?)
+        }
+    })
 }
 
 
@@ -247,11 +617,14 @@ async fn handle_connection(
 
 //register
 async fn handle_register(
-    stream: &mut TcpStream,
+    stream: &mut Conn,
     pool: SqlitePool,
-    body: &str,
+    throttle: &Throttle,
+    sessions: &SessionStore,
+    passwords: &PasswordPolicy,
+    request: &Request,
 ) -> anyhow::Result<()> {
-    let form = parse_form_urlencoded(body);
+    let form = parse_form_urlencoded(&request.body_str());
 
     let username = form
         .get("username")
@@ -268,7 +641,52 @@ async fn handle_register(
         .map(|s| s.as_str())
         .unwrap_or("");
 
-    println!("REGISTER: {} {} {}", username, password, role);
+    let invite = form
+        .get("invite")
+        .map(|s| s.as_str())
+        .unwrap_or("");
+
+    let email = form
+        .get("email")
+        .map(|s| s.as_str())
+        .unwrap_or("");
+
+    debug!(username, role, "Registration attempt");
+
+    // every registration attempt counts against the client's IP, separately
+    // from login failures so signing up doesn't eat into the login allowance
+    let ip_key = format!("register:{}", client_key(stream));
+    if let Err(wait) = throttle.reserve(&[&ip_key]) {
+        return send_too_many_requests(stream, wait).await;
+    }
+
+    // public sign-ups are lenders only; staff accounts need an invitation
+    if invite.is_empty() && !role.is_empty() && role != "lender" {
+        let html = b"<h1>Only lender accounts can be registered here</h1><a href=\"/register.html\">Back</a>";
+        return send_response(stream, 403, "text/html", html).await;
+    }
+
+    if username.trim().is_empty() {
+        let html = b"<h1>Username is required</h1><a href=\"/register.html\">Back</a>";
+        return send_response(stream, 400, "text/html", html).await;
+    }
+    if let Err(message) = passwords.check(username, password) {
+        let html = format!("<h1>{}</h1><a href=\"javascript:history.back()\">Back</a>", message);
+        return send_response(stream, 400, "text/html", html.as_bytes()).await;
+    }
+
+    // email is optional, but without one the user can't reset by themselves
+    let email = if email.trim().is_empty() {
+        None
+    } else {
+        match normalize_email(email) {
+            Some(email) => Some(email),
+            None => {
+                let html = b"<h1>Not a valid email address</h1><a href=\"javascript:history.back()\">Back</a>";
+                return send_response(stream, 400, "text/html", html).await;
+            }
+        }
+    };
 
     // if user already exists
     let exists: Option<(i64,)> =
@@ -282,29 +700,72 @@ async fn handle_register(
         send_html(stream, html).await?;
         return Ok(());
     }
-    let hashed = hash_password(password)?;
+
+    if let Some(email) = &email {
+        let taken: Option<(i64,)> = sqlx::query_as("SELECT id FROM users WHERE email = ?")
+            .bind(email)
+            .fetch_optional(&pool)
+            .await?;
+        if taken.is_some() {
+            let html = b"<h1>Email already in use</h1><a href=\"javascript:history.back()\">Back</a>";
+            return send_response(stream, 409, "text/html", html).await;
+        }
+    }
+    let hashed = hash_password(password).await?;
+
+    let mut tx = pool.begin().await?;
+
+    // redeeming the invitation and creating the user commit together,
+    // so a failed insert leaves the invitation usable
+    let role = if invite.is_empty() {
+        "lender".to_string()
+    } else {
+        let invited_role = sqlx::query_scalar::<_, String>(
+            "
+            UPDATE invitations
+            SET used_at = datetime('now')
+            WHERE token_hash = ? AND used_at IS NULL AND expires_at > datetime('now')
+            RETURNING role
+            ",
+        )
+        .bind(hash_token(invite))
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        match invited_role {
+            Some(role) => role,
+            None => {
+                let html = b"<h1>Invitation is invalid, expired or already used</h1><a href=\"/\">Back</a>";
+                return send_response(stream, 403, "text/html", html).await;
+            }
+        }
+    };
 
     // insert/register new user
-    sqlx::query(
-        "INSERT INTO users (username, password, role) VALUES (?, ?, ?)",
+    let user_id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO users (username, password, role, email) VALUES (?, ?, ?, ?) RETURNING id",
     )
     .bind(username)
     .bind(&hashed)
-    .bind(role)
-    .execute(&pool)
+    .bind(&role)
+    .bind(&email)
+    .fetch_one(&mut *tx)
     .await?;
 
-     println!("Registration successful. Redirecting to dashboard.");
+    tx.commit().await?;
+
+    info!(user_id, role = %role, "Registration successful");
 
     // redirect to correct dashboard based on registered role
-    match role {
+    let origin = session::Origin::new(stream, request, false);
+    match role.as_str() {
         "admin" => {
-            let token = create_session(&pool, username, "admin").await?;
-            send_redirect_with_session_cookie(stream, "/admin.html", &token).await?;
+            let issued = sessions.create(&pool, user_id, &origin).await?;
+            send_redirect_with_session_cookie(stream, "/admin.html", &issued).await?;
         }
         "lender" => {
-            let token = create_session(&pool, username, "lender").await?;
-            send_redirect_with_session_cookie(stream, "/lender.html", &token).await?;
+            let issued = sessions.create(&pool, user_id, &origin).await?;
+            send_redirect_with_session_cookie(stream, "/lender.html", &issued).await?;
         }
         _ => {
             let html = b"<h1>Unknown role</h1>";
@@ -317,11 +778,15 @@ async fn handle_register(
 
 //handle login
 async fn handle_login(
-    stream: &mut TcpStream,
+    stream: &mut Conn,
     pool: SqlitePool,
-    body: &str,
+    config: &Config,
+    throttle: &Throttle,
+    sessions: &SessionStore,
+    metrics: &Metrics,
+    request: &Request,
 ) -> anyhow::Result<()> {
-    let form = parse_form_urlencoded(body);
+    let form = parse_form_urlencoded(&request.body_str());
 
     let username = form
         .get("username")
@@ -333,720 +798,114 @@ async fn handle_login(
         .map(|s| s.as_str())
         .unwrap_or("");
 
-    println!("LOGIN: {} {}", username, password);
-
-    // Get password + role
-    let user: Option<(String, String)> =
-        sqlx::query_as(
-            "SELECT password, role FROM users WHERE username = ?",
-        )
-        .bind(username)
-        .fetch_optional(&pool)
-        .await?;
-
-    match user {
-    None => {
-        send_redirect(stream, "/register.html").await?;
-    }
-    Some((db_password, role)) => {
-        let valid = verify_password(password, &db_password)?;
-
-        if !valid {
-            let html = b"<h1>Invalid password</h1><a href=\"/\">Back</a>";
-            send_html(stream, html).await?;
-            return Ok(());
-        }
+    // "remember me" checkbox: a persistent cookie and the longer idle lifetime
+    let remember = form.contains_key("remember");
 
-        println!("Login success. Role = {}", role);
+    debug!(username, "Login attempt");
 
-        match role.as_str() {
-            "admin" => {
-                let token = create_session(&pool, username, "admin").await?;
-                send_redirect_with_session_cookie(stream, "/admin.html", &token).await?;
-            }
-            "lender" => {
-                let token = create_session(&pool, username, "lender").await?;
-                send_redirect_with_session_cookie(stream, "/lender.html", &token).await?;
-            }
-            _ => {
-                let html = b"<h1>Unknown role</h1>";
-                send_html(stream, html).await?;
-            }
-        }
+    // the attempt counts as a failure until the password checks out
+    let ip_key = client_key(stream);
+    let user_key = format!("user:{}", username);
+    if let Err(wait) = throttle.reserve(&[&ip_key, &user_key]) {
+        metrics.login(LoginResult::Throttled);
+        return send_too_many_requests(stream, wait).await;
     }
-}
-
-
-    Ok(())
-}
-
-
-//----------------------------------------------------------------------------------------------------------
-// admin dashboard fns
-
-async fn handle_admin_users(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-) -> anyhow::Result<()> {
-    let users = sqlx::query_as::<_, AdminUser>(
-    "
-    SELECT id, username, role
-    FROM users
-    "
-)
-.fetch_all(&pool)
-.await?;
-
-
-    let json = serde_json::to_vec(&users)?;
-    send_json(stream, &json).await
-}
-
-async fn handle_admin_books(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-) -> anyhow::Result<()> {
-
-    // First, sync all book availability counts with actual active loans
-    sync_book_availability(&pool).await?;
-
-    let rows = sqlx::query_as::<_, (
-        i64,            // bookid
-        String,         // title
-        String,         // author
-        String,         // isbn
-        Option<i64>,    // year_of_pub
-        Option<String>, // genre
-        i64,            // total_copies
-        i64             // available_copies
-    )>(
-        "
-        SELECT
-            bookid,
-            title,
-            author,
-            isbn,
-            year_of_pub,
-            genre,
-            total_copies,
-            available_copies
-        FROM books
-        "
-    )
-    .fetch_all(&pool)
-    .await?;
-
-    let books: Vec<AdminBook> = rows
-        .into_iter()
-        .map(|(bookid, title, author, isbn, year_of_pub, genre, total, available)| {
-            let checked_out = total - available;
-
-            let status = format!(
-                "{} available, {} checked out",
-                available, checked_out
-            );
-
-            AdminBook {
-                bookid,
-                title,
-                author,
-                isbn,
-                year_of_pub,
-                genre,
-                total_copies: total,
-                available_copies: available,
-                status,
-            }
-        })
-        .collect();
-
-    let json = serde_json::to_vec(&books)?;
-    send_json(stream, &json).await
-}
-
-/// Syncs available_copies for all books based on actual active loans
-async fn sync_book_availability(pool: &SqlitePool) -> anyhow::Result<()> {
-    // For each book, recalculate available_copies = total_copies - active_loans
-    sqlx::query(
-        "
-        UPDATE books
-        SET available_copies = total_copies - (
-            SELECT COUNT(*)
-            FROM loans
-            WHERE loans.loaned_bookid = books.bookid
-            AND loans.return_date IS NULL
-        )
-        "
-    )
-    .execute(pool)
-    .await?;
 
-    Ok(())
-}
-
-
-async fn handle_admin_loans(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-) -> anyhow::Result<()> {
-    let rows = sqlx::query_as::<_, (i64, String, String, String, String, Option<String>)>(
-        "
-        SELECT
-            l.loanid,
-            u.username,
-            b.title,
-            l.checkout_date,
-            l.due_date,
-            l.return_date
-        FROM loans l
-        JOIN users u ON u.id = l.loaned_to_user_id
-        JOIN books b ON b.bookid = l.loaned_bookid
-        "
-    )
-    .fetch_all(&pool)
-    .await?;
-
-    let result: Vec<AdminLoan> = rows
-        .into_iter()
-        .map(|(loanid, username, title, checkout_date, due_date, return_date)| {
-            let status = calculate_loan_status(&due_date, return_date.as_deref());
-
-            AdminLoan {
-                loanid,
-                username,
-                title,
-                checkout_date,
-                due_date,
-                status,
-            }
-        })
-        .collect();
-
-    let json = serde_json::to_vec(&result)?;
-    send_json(stream, &json).await
-}
-
-//admin crud OPS
-async fn handle_admin_add_book(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-    body: &str,
-) -> anyhow::Result<()> {
-
-    let input: AdminBookInput = serde_json::from_str(body)?;
-    if input.title.trim().is_empty()
-    || input.author.trim().is_empty()
-    || input.isbn.trim().is_empty()
-    || input.copies <= 0
-{
-    send_html(stream, b"Invalid book data").await?;
-    return Ok(());
-}
-
-    // check if book already exists by ISBN
-    let existing: Option<(i64, i64, i64)> =
+    // Get id + password + role
+    let user: Option<(i64, String, String)> =
         sqlx::query_as(
-            "SELECT bookid, total_copies, available_copies FROM books WHERE isbn = ?"
+            "SELECT id, password, role FROM users WHERE username = ?",
         )
-        .bind(&input.isbn)
+        .bind(username)
         .fetch_optional(&pool)
         .await?;
 
-    match existing {
-        Some((bookid, _, _)) => {
-            // increase copies
-            sqlx::query(
-                "
-                UPDATE books
-                SET total_copies = total_copies + ?,
-                    available_copies = available_copies + ?
-                WHERE bookid = ?
-                "
-            )
-            .bind(input.copies)
-            .bind(input.copies)
-            .bind(bookid)
-            .execute(&pool)
-            .await?;
-
-            send_html(stream, b"Book exists - copies increased").await
-        }
-
+    // unknown user, locked account and wrong password all look the same to the client
+    let login = &config.login;
+    let authenticated = match user {
         None => {
-            // insert new book
-            sqlx::query(
-                "
-                INSERT INTO books
-                (title, author, isbn, year_of_pub, genre, total_copies, available_copies)
-                VALUES (?, ?, ?, ?, ?, ?, ?)
-                "
-            )
-            .bind(&input.title)
-            .bind(&input.author)
-            .bind(&input.isbn)
-            .bind(input.year_of_pub)
-            .bind(&input.genre)
-            .bind(input.copies)
-            .bind(input.copies)
-            .execute(&pool)
-            .await?;
-
-            send_html(stream, b"Book added successfully").await
+            verify_dummy_password(password).await;
+            None
         }
-    }
-}
-
-async fn handle_admin_update_book(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-    bookid: i64,
-    body: &str,
-) -> anyhow::Result<()> {
-
-    let input: AdminBookInput = serde_json::from_str(body)?;
-
-    // fetch current counts
-    let (total, available): (i64, i64) = sqlx::query_as(
-        "SELECT total_copies, available_copies FROM books WHERE bookid = ?"
-    )
-    .bind(bookid)
-    .fetch_one(&pool)
-    .await?;
-
-    let checked_out = total - available;
-
-    if input.copies < checked_out {
-        send_html(
-            stream,
-            b"Cannot reduce total copies below number currently checked out",
-        ).await?;
-        return Ok(());
-    }
-
-    let new_available = input.copies - checked_out;
-
-    sqlx::query(
-        "
-        UPDATE books
-        SET title = ?, author = ?, isbn = ?, year_of_pub = ?, genre = ?,
-            total_copies = ?, available_copies = ?
-        WHERE bookid = ?
-        "
-    )
-    .bind(&input.title)
-    .bind(&input.author)
-    .bind(&input.isbn)
-    .bind(input.year_of_pub)
-    .bind(&input.genre)
-    .bind(input.copies)
-    .bind(new_available)
-    .bind(bookid)
-    .execute(&pool)
-    .await?;
-
-    send_html(stream, b"Book updated successfully").await
-}
-
-
-async fn handle_admin_delete_book(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-    bookid: i64,
-) -> anyhow::Result<()> {
-
-    // Check active loans
-    let active_loans: i64 = sqlx::query_scalar(
-        "
-        SELECT COUNT(*)
-        FROM loans
-        WHERE loaned_bookid = ?
-        AND return_date IS NULL
-        "
-    )
-    .bind(bookid)
-    .fetch_one(&pool)
-    .await?;
-
-    if active_loans > 0 {
-        let response = serde_json::json!({
-            "success": false,
-            "message": format!(
-                "Cannot delete book {}: {} active loan(s) exist",
-                bookid, active_loans
-            )
-        });
-
-        let json = serde_json::to_vec(&response)?;
-        send_json(stream, &json).await?;
-        return Ok(());
-    }
-
-    let mut tx = pool.begin().await?;
-
-    // 1️⃣ Delete historical loans
-    sqlx::query(
-        "
-        DELETE FROM loans
-        WHERE loaned_bookid = ?
-        "
-    )
-    .bind(bookid)
-    .execute(&mut *tx)
-    .await?;
-
-    sqlx::query(
-        "
-        DELETE FROM books
-        WHERE bookid = ?
-        "
-    )
-    .bind(bookid)
-    .execute(&mut *tx)
-    .await?;
-
-    tx.commit().await?;
-
-    let response = serde_json::json!({
-        "success": true,
-        "message": "Book and related loan history deleted"
-    });
-
-    let json = serde_json::to_vec(&response)?;
-    send_json(stream, &json).await
-}
-
-async fn handle_admin_overdue(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-) -> anyhow::Result<()> {
-
-    let rows = sqlx::query_as::<_, (String, String, String)>(
-        "
-        SELECT
-            u.username,
-            b.title,
-            l.due_date
-        FROM loans l
-        JOIN users u ON u.id = l.loaned_to_user_id
-        JOIN books b ON b.bookid = l.loaned_bookid
-        WHERE l.return_date IS NULL
-          AND date(l.due_date) < date('now')
-        "
-    )
-    .fetch_all(&pool)
-    .await?;
-
-    let today = chrono::Utc::now().date_naive();
-
-    let result: Vec<serde_json::Value> = rows
-        .into_iter()
-        .map(|(username, title, due_date)| {
-            let due = chrono::NaiveDate::parse_from_str(&due_date, "%Y-%m-%d").unwrap();
-            let days = (today - due).num_days();
-
-            serde_json::json!({
-                "username": username,
-                "title": title,
-                "due_date": due_date,
-                "days_overdue": days
-            })
-        })
-        .collect();
-
-    let json = serde_json::to_vec(&result)?;
-    send_json(stream, &json).await
-}
-
-//----------------------------------------------------------------------------------------------------------
-// lender dashboard fns
-
-async fn handle_lender_books(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-) -> anyhow::Result<()> {
-    // Sync availability before showing books to lenders
-    sync_book_availability(&pool).await?;
-
-    let books = sqlx::query_as::<_, LenderBook>(
-        "
-        SELECT 
-            bookid,
-            title,
-            author,
-            genre,
-            available_copies
-        FROM books
-        WHERE available_copies > 0
-        "
-    )
-    .fetch_all(&pool)
-    .await?;
-
-    let json = serde_json::to_vec(&books)?;
-    send_json(stream, &json).await
-}
-
-async fn handle_lender_search(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-    query: &str,
-) -> anyhow::Result<()> {
-    // Sync availability before searching
-    sync_book_availability(&pool).await?;
-
-    let q = format!("%{}%", query);
-
-    let books = sqlx::query_as::<_, LenderBook>(
-        "
-        SELECT
-            bookid,
-            title,
-            author,
-            isbn,
-            genre,
-            available_copies
-        FROM books
-        WHERE available_copies > 0
-          AND (
-            title LIKE ?
-            OR author LIKE ?
-            OR isbn LIKE ?
-            OR genre LIKE ?
-          )
-        "
-    )
-    .bind(&q)
-    .bind(&q)
-    .bind(&q)
-    .bind(&q)
-    .fetch_all(&pool)
-    .await?;
-
-    let json = serde_json::to_vec(&books)?;
-    send_json(stream, &json).await
-}
-
-
-async fn handle_lender_myloans(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-    username: &str,
-) -> anyhow::Result<()> {
-
-    let loans = sqlx::query_as::<_, (i64, String, String, String)>(
-        "
-        SELECT 
-            l.loanid,
-            b.title,
-            l.checkout_date,
-            l.due_date
-        FROM loans l
-        JOIN users u ON u.id = l.loaned_to_user_id
-        JOIN books b ON b.bookid = l.loaned_bookid
-        WHERE u.username = ?
-          AND l.return_date IS NULL
-          AND date(l.due_date) >= date('now')
-        "
-    )
-    .bind(username)
-    .fetch_all(&pool)
-    .await?;
-
-    let result: Vec<LenderLoan> = loans
-        .into_iter()
-        .map(|(loanid, title, checkout_date, due_date)| {
-            LenderLoan {
-                loanid,
-                title,
-                checkout_date,
-                due_date,
-                status: "Borrowed".to_string(),
+        Some((user_id, db_password, role)) => {
+            if !db::reserve_login_attempt(&pool, user_id, login.max_failed_attempts, login.lockout_minutes).await? {
+                verify_dummy_password(password).await;
+                None
+            } else if verify_password(password, &db_password).await? {
+                Some((user_id, role))
+            } else {
+                None
             }
-        })
-        .collect();
-
-    let json = serde_json::to_vec(&result)?;
-    send_json(stream, &json).await
-}
-
-
-async fn handle_lender_checkout(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-    username: &str,
-    bookid: i64,
-) -> anyhow::Result<()> {
-    let mut tx = pool.begin().await?;
-
-    // Get user id
-    let user = sqlx::query_as::<_, (i64,)>(
-        "SELECT id FROM users WHERE username = ?"
-    )
-    .bind(username)
-    .fetch_optional(&mut *tx)
-    .await?;
-
-    let user_id = match user {
-        Some((id,)) => id,
-        None => {
-            send_html(stream, b"<h1>User not found</h1>").await?;
-            return Ok(());
         }
     };
 
-
-    // Check availability
-    let row = sqlx::query_as::<_, (i64,)>(
-        "SELECT available_copies FROM books WHERE bookid = ?"
-    )
-    .bind(bookid)
-    .fetch_optional(&mut *tx)
-    .await?;
-
-    let available = match row {
-        Some((v,)) => v,
+    let (user_id, role) = match authenticated {
+        Some(found) => found,
         None => {
-            send_html(stream, b"<h1>Book not found</h1>").await?;
-            return Ok(());
+            info!(username, "Login failed");
+            metrics.login(LoginResult::Failure);
+            let html = b"<h1>Invalid username or password</h1><a href=\"/\">Back</a>";
+            return send_response(stream, 401, "text/html", html).await;
         }
     };
 
-
-    if available <= 0 {
-        send_html(stream, b"<h1>Book not available</h1>").await?;
-        return Ok(());
-    }
-
-    let already_borrowed = sqlx::query_scalar::<_, i64>(
-        "
-        SELECT COUNT(*)
-        FROM loans
-        WHERE loaned_to_user_id = ?
-        AND loaned_bookid = ?
-        AND return_date IS NULL
-        "
-    )
-    .bind(user_id)
-    .bind(bookid)
-    .fetch_one(&mut *tx)
-    .await?;
-
-    if already_borrowed > 0 {
-        send_html(stream, b"<h1>You already borrowed this book</h1>").await?;
-        return Ok(());
+    // with an authenticator enrolled the password is only half the login;
+    // the per-user counters stay until the code is checked too
+    throttle.refund(&ip_key);
+    if two_factor::is_enabled(&pool, user_id).await? {
+        info!(user_id, "Password accepted, waiting for second factor");
+        return two_factor::start_challenge(stream, &pool, sessions, user_id, remember).await;
     }
 
-    // Insert loan
-    sqlx::query(
-        "
-        INSERT INTO loans (loaned_to_user_id, loaned_bookid, checkout_date, due_date, return_date)
-        VALUES (?, ?, date('now'), date('now', '+14 days'), NULL)
-        "
-    )
-    .bind(user_id)
-    .bind(bookid)
-    .execute(&mut *tx)
-    .await?;
+    throttle.clear(&user_key);
+    db::clear_failed_logins(&pool, user_id).await?;
 
-    // Decrement copies
-    sqlx::query(
-        "
-        UPDATE books
-        SET available_copies = available_copies - 1
-        WHERE bookid = ?
-        "
-    )
-    .bind(bookid)
-    .execute(&mut *tx)
-    .await?;
+    info!(user_id, role = %role, "Login successful");
+    metrics.login(LoginResult::Success);
 
-    tx.commit().await?;
+    let origin = session::Origin::new(stream, request, remember);
+    match role.as_str() {
+        "admin" => {
+            let issued = sessions.create(&pool, user_id, &origin).await?;
+            // admins who must enrol land on the account page to set it up
+            let location = if two_factor::must_enroll(config, &role, false) {
+                "/account.html"
+            } else {
+                "/admin.html"
+            };
+            send_redirect_with_session_cookie(stream, location, &issued).await?;
+        }
+        "lender" => {
+            let issued = sessions.create(&pool, user_id, &origin).await?;
+            send_redirect_with_session_cookie(stream, "/lender.html", &issued).await?;
+        }
+        _ => {
+            let html = b"<h1>Unknown role</h1>";
+            send_html(stream, html).await?;
+        }
+    }
 
-    send_html(stream, b"<h1>Checkout successful</h1>").await
+    Ok(())
 }
 
-async fn handle_lender_return(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-    loanid: i64,
-) -> anyhow::Result<()> {
-    let mut tx = pool.begin().await?;
-
-    // Get bookid
-    let (bookid,): (i64,) = sqlx::query_as(
-        "SELECT loaned_bookid FROM loans WHERE loanid = ?"
-    )
-    .bind(loanid)
-    .fetch_one(&mut *tx)
-    .await?;
-
-    // Update loan
-    sqlx::query(
-        "UPDATE loans SET return_date = date('now') WHERE loanid = ?"
-    )
-    .bind(loanid)
-    .execute(&mut *tx)
-    .await?;
-
-    // Increment copies
-    sqlx::query(
-        "
-        UPDATE books
-        SET available_copies = available_copies + 1
-        WHERE bookid = ?
-        "
-    )
-    .bind(bookid)
-    .execute(&mut *tx)
-    .await?;
-
-    tx.commit().await?;
-
-    send_html(stream, b"<h1>Return successful</h1>").await
+/// Throttle key for the connection's client address
+fn client_key(stream: &Conn) -> String {
+    match stream.peer_ip {
+        Some(ip) => format!("ip:{}", ip),
+        None => "ip:unknown".to_string(),
+    }
 }
 
-async fn handle_lender_overdue(
-    stream: &mut TcpStream,
-    pool: SqlitePool,
-    username: &str,
-) -> anyhow::Result<()> {
-    let rows = sqlx::query_as::<_, (i64, String, String)>(
-        "
-        SELECT 
-            l.loanid,
-            b.title,
-            l.due_date
-        FROM loans l
-        JOIN users u ON u.id = l.loaned_to_user_id
-        JOIN books b ON b.bookid = l.loaned_bookid
-        WHERE u.username = ?
-          AND l.return_date IS NULL
-          AND date(l.due_date) < date('now')
-        "
-    )
-    .bind(username)
-    .fetch_all(&pool)
-    .await?;
-
-    let today = chrono::Utc::now().date_naive();
-
-    let result: Vec<OverdueLoan> = rows
-        .into_iter()
-        .map(|(loanid, title, due_date)| {
-            let due = chrono::NaiveDate::parse_from_str(&due_date, "%Y-%m-%d").unwrap();
-            let days_overdue = (today - due).num_days();
-
-            OverdueLoan {
-                loanid,
-                title,
-                due_date,
-                days_overdue,
-            }
-        })
-        .collect();
-
-    let json = serde_json::to_vec(&result)?;
-    send_json(stream, &json).await
+/// 429 with a `Retry-After` rounded up to whole seconds
+async fn send_too_many_requests(stream: &mut Conn, wait: std::time::Duration) -> anyhow::Result<()> {
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Response::new(429)
+        .header("Content-Type", "text/html")
+        .header("Retry-After", &secs.to_string())
+        .body(b"<h1>Too many attempts, try again later</h1><a href=\"/\">Back</a>")
+        .send(stream)
+        .await?;
+    Ok(())
 }
 
 
@@ -1054,172 +913,70 @@ async fn handle_lender_overdue(
 // parsing fns
 
 async fn send_redirect(
-    stream: &mut TcpStream,
+    stream: &mut Conn,
     location: &str,
 ) -> anyhow::Result<()> {
-    let response = format!(
-        "HTTP/1.1 302 Found\r\n\
-         Location: {}\r\n\
-         Content-Length: 0\r\n\
-         Connection: close\r\n\
-         \r\n",
-        location
-    );
-
-    stream.write_all(response.as_bytes()).await?;
+    Response::new(302)
+        .header("Location", location)
+        .send(stream)
+        .await?;
     Ok(())
 }
 
 
 async fn send_redirect_with_session_cookie(
-    stream: &mut TcpStream,
+    stream: &mut Conn,
     location: &str,
-    token: &str,
+    issued: &session::Issued,
 ) -> anyhow::Result<()> {
-    let response = format!(
-        "HTTP/1.1 302 Found\r\n\
-         Location: {}\r\n\
-         Set-Cookie: session={}; Path=/; HttpOnly\r\n\
-         Content-Length: 0\r\n\
-         Connection: close\r\n\
-         \r\n",
-        location, token
-    );
-
-    stream.write_all(response.as_bytes()).await?;
+    let [session_cookie, csrf_cookie] = issued.cookies(stream.is_secure());
+    Response::new(302)
+        .header("Location", location)
+        .cookie(session_cookie)
+        .cookie(csrf_cookie)
+        .send(stream)
+        .await?;
     Ok(())
 }
 
-/// Clears the session cookie and redirects to login
-async fn send_logout_redirect(stream: &mut TcpStream) -> anyhow::Result<()> {
-    let response = format!(
-        "HTTP/1.1 302 Found\r\n\
-         Location: /\r\n\
-         Set-Cookie: session=; Path=/; HttpOnly; Max-Age=0\r\n\
-         Content-Length: 0\r\n\
-         Connection: close\r\n\
-         \r\n"
-    );
-
-    stream.write_all(response.as_bytes()).await?;
+/// Clears the session cookies and redirects to login
+async fn send_logout_redirect(stream: &mut Conn) -> anyhow::Result<()> {
+    let [session_cookie, csrf_cookie] = session::removal_cookies(stream.is_secure());
+    Response::new(302)
+        .header("Location", "/")
+        .cookie(session_cookie)
+        .cookie(csrf_cookie)
+        .send(stream)
+        .await?;
     Ok(())
 }
 
 
-/// Looks up the session cookie token in the DB.
-/// Returns Some((username, role)) if a valid non-expired session exists, else None.
-async fn resolve_session(request: &str, pool: &SqlitePool) -> Option<(String, String)> {
-    let token = get_cookie_value(request, "session")?;
-
-    let row: Option<(String, String, String)> = sqlx::query_as(
-        "SELECT username, role, expires_at FROM sessions WHERE token = ?"
-    )
-    .bind(&token)
-    .fetch_optional(pool)
-    .await
-    .ok()?;
-
-    match row {
-        Some((username, role, expires_at)) => {
-            let expiry = chrono::NaiveDateTime::parse_from_str(&expires_at, "%Y-%m-%d %H:%M:%S")
-                .ok()?;
-            let now = chrono::Utc::now().naive_utc();
-
-            if now > expiry {
-                // Session expired — delete it silently
-                let _ = sqlx::query("DELETE FROM sessions WHERE token = ?")
-                    .bind(&token)
-                    .execute(pool)
-                    .await;
-                None
-            } else {
-                Some((username, role))
-            }
-        }
-        None => None,
-    }
-}
-
-/// Extracts a specific cookie value from the raw request string
-fn get_cookie_value(request: &str, name: &str) -> Option<String> {
-    let prefix = format!("{}=", name);
-    for line in request.lines() {
-        if line.starts_with("Cookie:") {
-            let cookies = line.strip_prefix("Cookie: ").unwrap_or(line);
-            for cookie in cookies.split(';') {
-                let cookie = cookie.trim();
-                if let Some(value) = cookie.strip_prefix(&prefix) {
-                    return Some(value.to_string());
-                }
-            }
-        }
-    }
-    None
-}
-
-/// Creates a session row in the DB and returns the token
-async fn create_session(pool: &SqlitePool, username: &str, role: &str) -> anyhow::Result<String> {
-    let token = generate_session_token();
-    // Session lasts 24 hours
-    let expires_at = (chrono::Utc::now().naive_utc() + chrono::Duration::hours(24))
-        .format("%Y-%m-%d %H:%M:%S")
-        .to_string();
-
-    sqlx::query(
-        "INSERT INTO sessions (token, username, role, expires_at) VALUES (?, ?, ?, ?)"
-    )
-    .bind(&token)
-    .bind(username)
-    .bind(role)
-    .bind(&expires_at)
-    .execute(pool)
-    .await?;
-
-    Ok(token)
-}
-
-/// Deletes a session from the DB by token
-async fn destroy_session(pool: &SqlitePool, request: &str) {
-    if let Some(token) = get_cookie_value(request, "session") {
-        let _ = sqlx::query("DELETE FROM sessions WHERE token = ?")
-            .bind(&token)
-            .execute(pool)
-            .await;
+async fn serve_file(stream: &mut Conn, request: &Request, path: &str) -> anyhow::Result<()> {
+    if !static_files::serve(stream, request, Path::new(path)).await? {
+        send_404(stream).await?;
     }
+    Ok(())
 }
 
-
-async fn serve_file(stream: &mut TcpStream, path: &str) -> anyhow::Result<()> {
-    let contents = fs::read(path)?;
-    send_response(stream, 200, "text/html", &contents).await
-}
-
-async fn send_404(stream: &mut TcpStream) -> anyhow::Result<()> {
+async fn send_404(stream: &mut Conn) -> anyhow::Result<()> {
     send_response(stream, 404, "text/html", b"<h1>404 Not Found</h1>").await
 }
 
-async fn send_html(stream: &mut TcpStream, body: &[u8]) -> anyhow::Result<()> {
-    send_response(stream, 200, "text/html", body).await
+async fn send_405(stream: &mut Conn, allowed: &[&str]) -> anyhow::Result<()> {
+    Response::new(405)
+        .header("Allow", &allowed.join(", "))
+        .header("Content-Type", "text/html")
+        .body(b"<h1>405 Method Not Allowed</h1>")
+        .send(stream)
+        .await?;
+    Ok(())
 }
 
-
-fn parse_request_line(request: &str) -> (String, String) {
-    let first = request.lines().next().unwrap_or("");
-    let parts: Vec<&str> = first.split_whitespace().collect();
-    if parts.len() >= 2 {
-        (parts[0].to_string(), parts[1].to_string())
-    } else {
-        ("".into(), "".into())
-    }
+async fn send_html(stream: &mut Conn, body: &[u8]) -> anyhow::Result<()> {
+    send_response(stream, 200, "text/html", body).await
 }
 
-fn extract_body(request: &str) -> &str {
-    if let Some(pos) = request.find("\r\n\r\n") {
-        &request[pos + 4..]
-    } else {
-        ""
-    }
-}
 
 fn parse_form_urlencoded(body: &str) -> HashMap<String, String> {
     let mut map = HashMap::new();
@@ -1232,70 +989,72 @@ fn parse_form_urlencoded(body: &str) -> HashMap<String, String> {
     map
 }
 
+/// Decodes `application/x-www-form-urlencoded` text: `+` is a space and
+/// `%XX` escapes are bytes. The decoded bytes are read as UTF-8 so multi-byte
+/// characters (`%C3%A9` -> `é`) survive; malformed escapes such as `%zz` or a
+/// trailing `%4` are kept literally, and invalid UTF-8 becomes U+FFFD.
 fn url_decode(input: &str) -> String {
-    input.replace('+', " ")
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(hi), Some(lo)) => {
+                        out.push(hi << 4 | lo);
+                        i += 2;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
 }
 
 
 async fn send_response(
-    stream: &mut TcpStream,
+    stream: &mut Conn,
     status: u16,
     content_type: &str,
     body: &[u8],
 ) -> anyhow::Result<()> {
-    let status_text = match status {
-        200 => "OK",
-        302 => "Found",
-        404 => "Not Found",
-        _ => "OK",
-    };
-
-    let header = format!(
-        "HTTP/1.1 {} {}\r\n\
-         Content-Type: {}\r\n\
-         Content-Length: {}\r\n\
-         Connection: close\r\n\
-         \r\n",
-        status,
-        status_text,
-        content_type,
-        body.len()
-    );
-
-    stream.write_all(header.as_bytes()).await?;
-    stream.write_all(body).await?;
+    Response::new(status)
+        .header("Content-Type", content_type)
+        .body(body)
+        .send(stream)
+        .await?;
     Ok(())
 }
 
 async fn send_json(
-    stream: &mut TcpStream,
+    stream: &mut Conn,
     body: &[u8],
 ) -> anyhow::Result<()> {
-    let header = format!(
-        "HTTP/1.1 200 OK\r\n\
-         Content-Type: application/json\r\n\
-         Content-Length: {}\r\n\
-         Connection: close\r\n\
-         \r\n",
-        body.len()
-    );
-
-    stream.write_all(header.as_bytes()).await?;
-    stream.write_all(body).await?;
-    Ok(())
+    send_response(stream, 200, "application/json", body).await
 }
 
 
-fn parse_query_param(path: &str, key: &str) -> Option<String> {
-    let parts: Vec<&str> = path.split('?').collect();
-    if parts.len() < 2 {
-        return None;
-    }
-
-    for pair in parts[1].split('&') {
+fn parse_query_param(query: &str, key: &str) -> Option<String> {
+    for pair in query.split('&') {
         let mut kv = pair.splitn(2, '=');
         if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
-            if k == key {
+            if url_decode(k) == key {
                 return Some(url_decode(v));
             }
         }
@@ -1303,21 +1062,319 @@ fn parse_query_param(path: &str, key: &str) -> Option<String> {
     None
 }
 
-fn calculate_loan_status(
-    due_date: &str,
-    return_date: Option<&str>,
-) -> String {
-    if return_date.is_some() {
-        return "Returned".to_string();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+
+    /// Serves `config` on an ephemeral local port with an in-memory database,
+    /// returned so tests can set up rows. Sending on the returned channel
+    /// shuts the server down.
+    async fn start_server(
+        mut config: Config,
+    ) -> (SocketAddr, SqlitePool, oneshot::Sender<()>, JoinHandle<anyhow::Result<()>>) {
+        config.database.path = ":memory:".to_string();
+        config.session.key_file = std::env::temp_dir()
+            .join(format!("library-test-{:016x}.key", rand::random::<u64>()))
+            .display()
+            .to_string();
+
+        let pool = get_db_pool(&config.database).await.unwrap();
+        migrate::run(&pool).await.unwrap();
+        let sessions = SessionStore::load(&config.session).unwrap();
+        std::fs::remove_file(&config.session.key_file).unwrap();
+
+        let state = Arc::new(AppState {
+            pool: pool.clone(),
+            sessions,
+            router: build_router(),
+            throttle: Throttle::new(config.login.throttle_free_attempts, config.login.throttle_max_backoff()),
+            passwords: PasswordPolicy::load(&config.password).unwrap(),
+            mailer: mailer::from_config(&config.mail),
+            metrics: Metrics::new(),
+            config,
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let limiter = Arc::new(Semaphore::new(state.config.server.max_connections));
+        let server = tokio::spawn(serve(listener, None, state, limiter, async move {
+            let _ = stop_rx.await;
+            Ok(())
+        }));
+        (addr, pool, stop_tx, server)
+    }
+
+    /// Everything the server sends until it closes the connection
+    async fn read_response(client: &mut TcpStream) -> String {
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut received))
+            .await
+            .expect("server did not close the connection")
+            .unwrap();
+        String::from_utf8_lossy(&received).into_owned()
     }
 
-    let today = chrono::Utc::now().date_naive();
-    let due = chrono::NaiveDate::parse_from_str(due_date, "%Y-%m-%d")
-        .unwrap();
+    #[tokio::test]
+    async fn slow_request_gets_408() {
+        let mut config = Config::default();
+        config.server.request_timeout_secs = 1;
+        let (addr, _, stop, server) = start_server(config).await;
 
-    if due < today {
-        "Overdue".to_string()
-    } else {
-        "Borrowed".to_string()
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        // headers started but never finished
+        client.write_all(b"GET /healthz HTTP/1.1\r\nHost: x\r\n").await.unwrap();
+        let response = read_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_get_431() {
+        let mut config = Config::default();
+        config.server.max_header_bytes = 256;
+        let (addr, _, stop, server) = start_server(config).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET /healthz HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(1024));
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+
+        // a request within the limit on a fresh connection is still served
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_cap_wait_for_a_free_slot() {
+        let mut config = Config::default();
+        config.server.max_connections = 1;
+        let (addr, _, stop, server) = start_server(config).await;
+
+        // holds the only slot without sending anything
+        let first = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // the kernel completes the handshake, but the server doesn't pick it up
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second
+            .write_all(b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut byte = [0u8; 1];
+        let early = tokio::time::timeout(Duration::from_millis(300), second.read(&mut byte)).await;
+        assert!(early.is_err(), "second connection was served while the cap was reached");
+
+        // freeing the slot lets it through
+        drop(first);
+        let response = read_response(&mut second).await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    const PASSWORD: &str = "correct horse battery";
+
+    async fn add_user(pool: &SqlitePool, username: &str, role: &str) {
+        sqlx::query("INSERT INTO users (username, password, role) VALUES (?, ?, ?)")
+            .bind(username)
+            .bind(hash_password(PASSWORD).await.unwrap())
+            .bind(role)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    /// Sends one request on a fresh connection and returns the whole response
+    async fn send(addr: SocketAddr, method: &str, path: &str, headers: &str, body: &str) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            headers,
+            body.len(),
+            body
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        read_response(&mut client).await
+    }
+
+    /// Logs in and returns a `Cookie:` header line carrying the session and
+    /// CSRF cookies, plus the CSRF token on its own
+    async fn login(addr: SocketAddr, username: &str) -> (String, String) {
+        let body = format!("username={}&password={}", username, PASSWORD.replace(' ', "+"));
+        let headers = "Content-Type: application/x-www-form-urlencoded\r\n";
+        let response = send(addr, "POST", "/login", headers, &body).await;
+        assert!(response.starts_with("HTTP/1.1 302 "), "{}", response);
+
+        let cookies: Vec<(&str, &str)> = response
+            .lines()
+            .filter_map(|line| line.strip_prefix("Set-Cookie: "))
+            .filter_map(|cookie| cookie.split(';').next()?.split_once('='))
+            .collect();
+        let csrf = cookies.iter().find(|(name, _)| *name == session::CSRF_COOKIE).unwrap().1.to_string();
+        let header = cookies.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>();
+        (format!("Cookie: {}\r\n", header.join("; ")), csrf)
+    }
+
+    fn status(response: &str) -> u16 {
+        response[9..12].parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn guarded_routes_need_a_session_with_the_right_role() {
+        let (addr, pool, stop, server) = start_server(Config::default()).await;
+        add_user(&pool, "alice", "admin").await;
+        add_user(&pool, "bob", "lender").await;
+
+        // no session at all
+        let response = send(addr, "GET", "/admin/api/users", "", "").await;
+        assert_eq!(status(&response), 401, "{}", response);
+        assert!(response.contains(r#""error":"unauthorized""#), "{}", response);
+        assert_eq!(status(&send(addr, "GET", "/lender/api/books", "", "").await), 401);
+        assert_eq!(status(&send(addr, "GET", "/account/api/2fa", "", "").await), 401);
+        // a cookie that names no session is no better
+        let forged = "Cookie: session=0123456789abcdef\r\n";
+        assert_eq!(status(&send(addr, "GET", "/admin/api/users", forged, "").await), 401);
+
+        // a lender on an admin route
+        let (lender, _) = login(addr, "bob").await;
+        let response = send(addr, "GET", "/admin/api/users", &lender, "").await;
+        assert_eq!(status(&response), 403, "{}", response);
+        assert!(response.contains(r#""error":"forbidden""#), "{}", response);
+        assert_eq!(status(&send(addr, "GET", "/lender/api/books", &lender, "").await), 200);
+        assert_eq!(status(&send(addr, "GET", "/account/api/2fa", &lender, "").await), 200);
+
+        // an admin gets through to the handler
+        let (admin, _) = login(addr, "alice").await;
+        let response = send(addr, "GET", "/admin/api/users", &admin, "").await;
+        assert_eq!(status(&response), 200, "{}", response);
+        assert!(response.contains(r#""username":"bob""#), "{}", response);
+        assert_eq!(status(&send(addr, "GET", "/lender/api/books", &admin, "").await), 403);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_failed_session_lookup_is_a_500_not_a_logout() {
+        let (addr, pool, stop, server) = start_server(Config::default()).await;
+        add_user(&pool, "alice", "admin").await;
+        let (admin, _) = login(addr, "alice").await;
+
+        sqlx::query("DROP TABLE sessions").execute(&pool).await.unwrap();
+        let response = send(addr, "GET", "/admin/api/users", &admin, "").await;
+        assert_eq!(status(&response), 500, "{}", response);
+        assert!(response.contains(r#""message":"internal server error""#), "{}", response);
+        assert!(!response.contains("sessions"), "{}", response);
+        // without a cookie there is nothing to look up
+        assert_eq!(status(&send(addr, "GET", "/admin/api/users", "", "").await), 401);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn state_changes_need_the_sessions_csrf_token() {
+        let (addr, pool, stop, server) = start_server(Config::default()).await;
+        add_user(&pool, "alice", "admin").await;
+        add_user(&pool, "bob", "lender").await;
+        let (admin, admin_csrf) = login(addr, "alice").await;
+        let (_, lender_csrf) = login(addr, "bob").await;
+
+        let cases = [
+            ("POST", "/account/api/2fa/setup"),
+            ("PUT", "/account/api/email"),
+            ("DELETE", "/admin/api/sessions/999"),
+        ];
+        for (method, path) in cases {
+            // missing, wrong, or another session's token
+            let tokens = [
+                String::new(),
+                "X-CSRF-Token: nope\r\n".to_string(),
+                format!("X-CSRF-Token: {}\r\n", lender_csrf),
+            ];
+            for token in tokens {
+                let response = send(addr, method, path, &format!("{}{}", admin, token), "").await;
+                assert_eq!(status(&response), 403, "{} {} with {:?}: {}", method, path, token, response);
+                assert!(response.contains("missing or invalid CSRF token"), "{}", response);
+            }
+        }
+
+        // with the right token each one reaches its handler
+        let token = format!("{}X-CSRF-Token: {}\r\n", admin, admin_csrf);
+        assert_eq!(status(&send(addr, "POST", "/account/api/2fa/setup", &token, "").await), 200);
+        let response = send(addr, "PUT", "/account/api/email", &token, "{}").await;
+        assert_eq!(status(&response), 400, "{}", response);
+        assert_eq!(status(&send(addr, "DELETE", "/admin/api/sessions/999", &token, "").await), 404);
+
+        // reads need no token
+        assert_eq!(status(&send(addr, "GET", "/account/api/2fa", &admin, "").await), 200);
+        assert_eq!(status(&send(addr, "HEAD", "/account/api/2fa", &admin, "").await), 200);
+        assert_eq!(status(&send(addr, "GET", "/admin/api/users", &admin, "").await), 200);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn url_decode_escapes_and_plus() {
+        assert_eq!(url_decode("C%2B%2B"), "C++");
+        assert_eq!(url_decode("Tolkien+%26+Lewis"), "Tolkien & Lewis");
+        assert_eq!(url_decode("a+b%20c"), "a b c");
+    }
+
+    #[test]
+    fn url_decode_non_ascii() {
+        assert_eq!(url_decode("Caf%C3%A9"), "Café");
+        assert_eq!(url_decode("%E6%9D%B1%E4%BA%AC"), "東京");
+        // already-decoded text passes through untouched
+        assert_eq!(url_decode("Gabriel García Márquez"), "Gabriel García Márquez");
+    }
+
+    #[test]
+    fn url_decode_keeps_malformed_escapes() {
+        assert_eq!(url_decode("100%zz"), "100%zz");
+        assert_eq!(url_decode("abc%4"), "abc%4");
+        assert_eq!(url_decode("%"), "%");
+        assert_eq!(url_decode("%%41"), "%A");
+    }
+
+    #[test]
+    fn url_decode_invalid_utf8_becomes_replacement_char() {
+        assert_eq!(url_decode("a%FFb"), "a\u{FFFD}b");
+        // a multi-byte sequence cut short
+        assert_eq!(url_decode("%C3"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn query_and_form_params_are_decoded() {
+        let query = "title=Les+Mis%C3%A9rables&author=Victor%20Hugo&q=C%2B%2B";
+        assert_eq!(parse_query_param(query, "title").as_deref(), Some("Les Misérables"));
+        assert_eq!(parse_query_param(query, "author").as_deref(), Some("Victor Hugo"));
+        assert_eq!(parse_query_param(query, "q").as_deref(), Some("C++"));
+        assert_eq!(parse_query_param(query, "missing"), None);
+
+        let form = parse_form_urlencoded("username=j%C3%BCrgen&note=Tolkien+%26+Lewis");
+        assert_eq!(form["username"], "jürgen");
+        assert_eq!(form["note"], "Tolkien & Lewis");
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use sqlx::SqlitePool;
+
+use crate::error::{ApiError, ApiResult};
+use crate::http::{Conn, Request};
+use crate::response::Response;
+use crate::router::{BoxFuture, Ctx, Router};
+use crate::session::constant_time_eq;
+
+/// Upper bounds (seconds) of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub fn routes(router: &mut Router) {
+    // public to the router: the handler accepts an admin session or the token
+    router.get("/metrics", metrics);
+}
+
+fn metrics(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let token = ctx.config.metrics.token.as_deref();
+        if !ctx.has_role("admin") && !bearer_matches(ctx.request, token) {
+            return Err(match ctx.session {
+                Some(_) => ApiError::Forbidden("insufficient role".to_string()),
+                None => ApiError::Unauthorized("admin session or metrics token required".to_string()),
+            });
+        }
+        handle_metrics(ctx.stream, ctx.pool, ctx.metrics).await
+    })
+}
+
+async fn handle_metrics(stream: &mut Conn, pool: SqlitePool, metrics: &Metrics) -> ApiResult {
+    let body = metrics.render(&pool).await?;
+    Response::new(200)
+        .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body(body.as_bytes())
+        .send(stream)
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Whether the request carries `Authorization: Bearer <token>`; always
+/// false when no token is configured
+fn bearer_matches(request: &Request, token: Option<&str>) -> bool {
+    let sent = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (sent, token) {
+        (Some(sent), Some(token)) => constant_time_eq(sent.trim().as_bytes(), token.as_bytes()),
+        _ => false,
+    }
+}
+
+/// How a login attempt ended, as counted in `library_logins_total`
+#[derive(Clone, Copy)]
+pub enum LoginResult {
+    Success,
+    /// wrong password or code, unknown user or locked account
+    Failure,
+    /// turned away by the backoff before the password was checked
+    Throttled,
+}
+
+impl LoginResult {
+    const ALL: [LoginResult; 3] = [LoginResult::Success, LoginResult::Failure, LoginResult::Throttled];
+
+    fn label(self) -> &'static str {
+        match self {
+            LoginResult::Success => "success",
+            LoginResult::Failure => "failure",
+            LoginResult::Throttled => "throttled",
+        }
+    }
+}
+
+/// Latency histogram and status counts of one method + route
+#[derive(Default)]
+struct RouteStats {
+    statuses: BTreeMap<u16, u64>,
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum_secs: f64,
+}
+
+/// In-process counters, rendered in the Prometheus text format on scrape.
+/// Database-derived values (pool usage, loans, copies) are read at scrape
+/// time instead of being tracked.
+#[derive(Default)]
+pub struct Metrics {
+    /// keyed by (method, route pattern), so ids in paths don't multiply series
+    requests: Mutex<BTreeMap<(&'static str, &'static str), RouteStats>>,
+    connections: AtomicI64,
+    logins: [AtomicU64; 3],
+}
+
+/// Decrements the open connection gauge when the connection ends
+pub struct ConnectionGuard<'a>(&'a Metrics);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Counts an open connection until the guard is dropped
+    pub fn connection(&self) -> ConnectionGuard<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self)
+    }
+
+    pub fn observe_request(&self, method: &str, route: &'static str, status: u16, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = requests.entry((method_label(method), route)).or_default();
+        *stats.statuses.entry(status).or_default() += 1;
+        for (bucket, bound) in stats.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        stats.count += 1;
+        stats.sum_secs += secs;
+    }
+
+    pub fn login(&self, result: LoginResult) {
+        self.logins[result as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The full exposition, counters first, then values read from the database
+    pub async fn render(&self, pool: &SqlitePool) -> anyhow::Result<String> {
+        let mut out = String::new();
+        self.render_requests(&mut out)?;
+
+        gauge(
+            &mut out,
+            "library_http_connections_active",
+            "Client connections currently open",
+            self.connections.load(Ordering::Relaxed),
+        )?;
+
+        writeln!(out, "# HELP library_logins_total Login attempts by outcome")?;
+        writeln!(out, "# TYPE library_logins_total counter")?;
+        for result in LoginResult::ALL {
+            let count = self.logins[result as usize].load(Ordering::Relaxed);
+            writeln!(out, "library_logins_total{{result=\"{}\"}} {}", result.label(), count)?;
+        }
+
+        let size = pool.size() as usize;
+        let idle = pool.num_idle();
+        writeln!(out, "# HELP library_db_pool_connections SQLite pool connections by state")?;
+        writeln!(out, "# TYPE library_db_pool_connections gauge")?;
+        writeln!(out, "library_db_pool_connections{{state=\"idle\"}} {}", idle)?;
+        writeln!(out, "library_db_pool_connections{{state=\"in_use\"}} {}", size.saturating_sub(idle))?;
+        gauge(
+            &mut out,
+            "library_db_pool_max_connections",
+            "Most connections the SQLite pool will open",
+            pool.options().get_max_connections(),
+        )?;
+
+        let (active, overdue) = sqlx::query_as::<_, (i64, i64)>(
+            "
+            SELECT COUNT(*), COALESCE(SUM(date(due_date) < date('now')), 0)
+            FROM loans
+            WHERE return_date IS NULL
+            ",
+        )
+        .fetch_one(pool)
+        .await?;
+        let (books, total, available) = sqlx::query_as::<_, (i64, i64, i64)>(
+            "SELECT COUNT(*), COALESCE(SUM(total_copies), 0), COALESCE(SUM(available_copies), 0) FROM books",
+        )
+        .fetch_one(pool)
+        .await?;
+
+        gauge(&mut out, "library_loans_active", "Books currently checked out", active)?;
+        gauge(&mut out, "library_loans_overdue", "Checked-out books past their due date", overdue)?;
+        gauge(&mut out, "library_books", "Titles in the catalogue", books)?;
+        gauge(&mut out, "library_book_copies_total", "Copies owned across all titles", total)?;
+        gauge(&mut out, "library_book_copies_available", "Copies on the shelf across all titles", available)?;
+
+        Ok(out)
+    }
+
+    fn render_requests(&self, out: &mut String) -> std::fmt::Result {
+        let requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+
+        writeln!(out, "# HELP library_http_requests_total HTTP requests by route and status")?;
+        writeln!(out, "# TYPE library_http_requests_total counter")?;
+        for ((method, route), stats) in requests.iter() {
+            for (status, count) in &stats.statuses {
+                writeln!(
+                    out,
+                    "library_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                    method, route, status, count
+                )?;
+            }
+        }
+
+        writeln!(out, "# HELP library_http_request_duration_seconds Time to handle a request, by route")?;
+        writeln!(out, "# TYPE library_http_request_duration_seconds histogram")?;
+        for ((method, route), stats) in requests.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, route);
+            for (count, bound) in stats.buckets.iter().zip(LATENCY_BUCKETS) {
+                writeln!(
+                    out,
+                    "library_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                )?;
+            }
+            writeln!(
+                out,
+                "library_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, stats.count
+            )?;
+            writeln!(out, "library_http_request_duration_seconds_sum{{{}}} {}", labels, stats.sum_secs)?;
+            writeln!(out, "library_http_request_duration_seconds_count{{{}}} {}", labels, stats.count)?;
+        }
+        Ok(())
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) -> std::fmt::Result {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} gauge", name)?;
+    writeln!(out, "{} {}", name, value)
+}
+
+/// Methods outside the usual set share one label, so clients can't create
+/// series at will
+fn method_label(method: &str) -> &'static str {
+    match method {
+        "GET" => "GET",
+        "HEAD" => "HEAD",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "DELETE" => "DELETE",
+        "OPTIONS" => "OPTIONS",
+        "PATCH" => "PATCH",
+        _ => "OTHER",
+    }
+}
@@ -0,0 +1,302 @@
+use sqlx::{Executor, SqliteConnection, SqlitePool};
+use tracing::{info, warn};
+
+use crate::config::MigrateAction;
+use crate::db;
+
+/// One numbered schema change, read from `migrations/` at build time
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+/// Every migration this build knows, oldest first. Add new ones at the end as
+/// `NNNN_name.up.sql` / `NNNN_name.down.sql`; never edit one that has shipped.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        up: include_str!("../migrations/0001_initial.up.sql"),
+        down: include_str!("../migrations/0001_initial.down.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "hash_invitation_tokens",
+        up: include_str!("../migrations/0002_hash_invitation_tokens.up.sql"),
+        down: include_str!("../migrations/0002_hash_invitation_tokens.down.sql"),
+    },
+];
+
+/// Schema version this build expects: its newest migration
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Applied versions and when, oldest first. Only reads: without the
+/// bookkeeping table (a new or pre-migrations database) nothing is applied.
+async fn applied(pool: &SqlitePool) -> anyhow::Result<Vec<(i64, String, String)>> {
+    if !db::table_exists(pool, "schema_migrations").await? {
+        return Ok(Vec::new());
+    }
+    let rows = sqlx::query_as::<_, (i64, String, String)>(
+        "SELECT version, name, applied_at FROM schema_migrations ORDER BY version",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Brings tables from before migrations to the shape the baseline migration
+/// expects, so its `IF NOT EXISTS` statements can fill in the rest. Runs in
+/// the baseline's transaction; a new database has no `users` and is left alone.
+async fn adopt_legacy_schema(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    if !db::table_exists(&mut *conn, "users").await? {
+        return Ok(());
+    }
+    info!("Adopting a database created before schema migrations");
+    db::add_column_if_missing(conn, "users", "email", "TEXT").await?;
+    // sessions from before they were keyed to user ids are dropped rather
+    // than converted; everyone just logs in again
+    if !db::column_exists(&mut *conn, "sessions", "user_id").await? {
+        conn.execute("DROP TABLE IF EXISTS sessions").await?;
+    }
+    Ok(())
+}
+
+/// Newest applied version, 0 for an empty database
+pub async fn current_version(pool: &SqlitePool) -> anyhow::Result<i64> {
+    if !db::table_exists(pool, "schema_migrations").await? {
+        return Ok(0);
+    }
+    let version = sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(version) FROM schema_migrations")
+        .fetch_one(pool)
+        .await?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Migrations not yet applied, oldest first. Refuses a database that has
+/// been migrated by a newer build, rather than run against a schema it
+/// doesn't know.
+async fn pending(pool: &SqlitePool) -> anyhow::Result<Vec<&'static Migration>> {
+    let applied = applied(pool).await?;
+    if let Some((version, name, _)) = applied.iter().find(|(v, _, _)| find(*v).is_none()) {
+        anyhow::bail!(
+            "database has migration {:04}_{} which this build doesn't know; upgrade the binary or roll it back with a newer one",
+            version,
+            name
+        );
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|m| !applied.iter().any(|(v, _, _)| *v == m.version))
+        .collect())
+}
+
+fn find(version: i64) -> Option<&'static Migration> {
+    MIGRATIONS.iter().find(|m| m.version == version)
+}
+
+/// Applies every pending migration, each in its own transaction together
+/// with its `schema_migrations` row (and, for the baseline, the bookkeeping
+/// table and legacy adoption)
+pub async fn run(pool: &SqlitePool) -> anyhow::Result<()> {
+    for migration in pending(pool).await? {
+        let mut tx = pool.begin().await?;
+        (&mut *tx).execute(
+            "
+            CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TEXT NOT NULL
+            )
+            ",
+        )
+        .await?;
+        if migration.version == 1 {
+            adopt_legacy_schema(&mut tx).await?;
+        }
+        (&mut *tx)
+            .execute(migration.up)
+            .await
+            .map_err(|e| anyhow::anyhow!("migration {:04}_{} failed: {}", migration.version, migration.name, e))?;
+        sqlx::query("INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, ?, datetime('now'))")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        info!(version = migration.version, name = migration.name, "Applied migration");
+    }
+    Ok(())
+}
+
+/// Reverts the newest `steps` applied migrations, newest first
+pub async fn rollback(pool: &SqlitePool, steps: usize) -> anyhow::Result<()> {
+    let applied = applied(pool).await?;
+    for (version, name, _) in applied.iter().rev().take(steps) {
+        let migration = find(*version).ok_or_else(|| {
+            anyhow::anyhow!("migration {:04}_{} is unknown to this build and can't be rolled back", version, name)
+        })?;
+        let mut tx = pool.begin().await?;
+        (&mut *tx)
+            .execute(migration.down)
+            .await
+            .map_err(|e| anyhow::anyhow!("rollback of {:04}_{} failed: {}", migration.version, migration.name, e))?;
+        sqlx::query("DELETE FROM schema_migrations WHERE version = ?")
+            .bind(migration.version)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        info!(version = migration.version, name = migration.name, "Rolled back migration");
+    }
+    Ok(())
+}
+
+/// Startup step for commands that use the database: migrate, or with
+/// auto-migration off only warn, leaving `/readyz` to report the mismatch
+pub async fn on_startup(pool: &SqlitePool, auto_migrate: bool) -> anyhow::Result<()> {
+    if auto_migrate {
+        return run(pool).await;
+    }
+    let pending = pending(pool).await?;
+    if !pending.is_empty() {
+        warn!(
+            pending = pending.len(),
+            "Schema migrations pending and auto-migration is off; run `library migrate up`"
+        );
+    }
+    Ok(())
+}
+
+/// The `migrate` subcommand
+pub async fn command(pool: &SqlitePool, action: MigrateAction) -> anyhow::Result<()> {
+    match action {
+        MigrateAction::Up => run(pool).await?,
+        MigrateAction::Down { steps } => rollback(pool, steps).await?,
+        MigrateAction::Status => {}
+    }
+    print_status(pool).await
+}
+
+async fn print_status(pool: &SqlitePool) -> anyhow::Result<()> {
+    let applied = applied(pool).await?;
+    println!("{:<8} {:<24} APPLIED", "VERSION", "NAME");
+    for migration in MIGRATIONS {
+        let applied_at = applied
+            .iter()
+            .find(|(v, _, _)| *v == migration.version)
+            .map_or("pending", |(_, _, at)| at.as_str());
+        println!("{:<8} {:<24} {}", format!("{:04}", migration.version), migration.name, applied_at);
+    }
+    for (version, name, applied_at) in applied.iter().filter(|(v, _, _)| find(*v).is_none()) {
+        println!("{:<8} {:<24} {} (unknown to this build)", format!("{:04}", version), name, applied_at);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+
+    async fn memory_pool() -> SqlitePool {
+        let config = DatabaseConfig {
+            path: ":memory:".to_string(),
+            ..DatabaseConfig::default()
+        };
+        db::get_db_pool(&config).await.unwrap()
+    }
+
+    /// Every table, index and trigger with its definition
+    async fn schema(pool: &SqlitePool) -> Vec<(String, String, Option<String>)> {
+        sqlx::query_as("SELECT type, name, sql FROM sqlite_master ORDER BY type, name")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    /// Tables as they were before migrations existed: no `email`, sessions
+    /// keyed by username
+    async fn legacy_pool() -> SqlitePool {
+        let pool = memory_pool().await;
+        pool.execute(
+            "
+            CREATE TABLE users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT UNIQUE NOT NULL,
+                password TEXT NOT NULL,
+                role TEXT NOT NULL
+            );
+            CREATE TABLE sessions (token TEXT PRIMARY KEY, username TEXT NOT NULL);
+            INSERT INTO users (username, password, role) VALUES ('alice', 'x', 'admin');
+            INSERT INTO sessions (token, username) VALUES ('t', 'alice');
+            ",
+        )
+        .await
+        .unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn up_applies_everything_and_down_reverts_newest_first() {
+        let pool = memory_pool().await;
+        assert_eq!(current_version(&pool).await.unwrap(), 0);
+        assert_eq!(pending(&pool).await.unwrap().len(), MIGRATIONS.len());
+
+        run(&pool).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+        assert!(pending(&pool).await.unwrap().is_empty());
+        assert!(db::column_exists(&pool, "invitations", "token_hash").await.unwrap());
+        // applying again is a no-op
+        run(&pool).await.unwrap();
+
+        rollback(&pool, 1).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version() - 1);
+        assert!(db::column_exists(&pool, "invitations", "token").await.unwrap());
+
+        rollback(&pool, MIGRATIONS.len()).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), 0);
+        assert!(!db::table_exists(&pool, "users").await.unwrap());
+
+        run(&pool).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+    }
+
+    #[tokio::test]
+    async fn pending_with_auto_migrate_off_leaves_the_schema_alone() {
+        let pool = memory_pool().await;
+        on_startup(&pool, false).await.unwrap();
+        command(&pool, MigrateAction::Status).await.unwrap();
+        assert!(schema(&pool).await.is_empty());
+
+        let pool = legacy_pool().await;
+        let before = schema(&pool).await;
+        on_startup(&pool, false).await.unwrap();
+        command(&pool, MigrateAction::Status).await.unwrap();
+        assert_eq!(pending(&pool).await.unwrap().len(), MIGRATIONS.len());
+        assert_eq!(schema(&pool).await, before);
+    }
+
+    #[tokio::test]
+    async fn legacy_databases_are_adopted_by_the_baseline() {
+        let pool = legacy_pool().await;
+        on_startup(&pool, true).await.unwrap();
+
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+        assert!(db::column_exists(&pool, "users", "email").await.unwrap());
+        let users = sqlx::query_scalar::<_, String>("SELECT username FROM users")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(users, ["alice"]);
+        // old sessions are dropped and the table recreated in the new shape
+        assert!(db::column_exists(&pool, "sessions", "user_id").await.unwrap());
+        let sessions = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM sessions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sessions, 0);
+    }
+}
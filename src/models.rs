@@ -6,6 +6,24 @@ pub struct AdminUser {
     pub id: i64,
     pub username: String,
     pub role: String,
+    /// set while the account is locked out after failed logins
+    pub locked_until: Option<String>,
+    /// has a confirmed TOTP authenticator
+    pub two_factor: bool,
+}
+
+/// One login as shown in the session lists
+#[derive(Serialize, FromRow)]
+pub struct SessionInfo {
+    pub id: i64,
+    pub created_at: String,
+    pub last_seen_at: String,
+    pub expires_at: String,
+    pub remember: bool,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// the session making this request
+    pub current: bool,
 }
 
 #[derive(Serialize, FromRow)]
@@ -41,6 +59,21 @@ pub struct AdminBookInput {
     pub copies: i64,
 }
 
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct InvitationInput {
+    /// defaults to `admin`
+    pub role: Option<String>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Invitation {
+    pub id: i64,
+    pub role: String,
+    pub created_by: String,
+    pub expires_at: String,
+}
+
 #[derive(Serialize, FromRow)]
 pub struct LenderBook {
     pub bookid: i64,
@@ -65,4 +98,5 @@ pub struct OverdueLoan {
     pub title: String,
     pub due_date: String,
     pub days_overdue: i64,
+    pub fine: i64,
 }
\ No newline at end of file
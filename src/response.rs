@@ -0,0 +1,353 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::http::Conn;
+
+/// Reason phrase for a status code
+pub fn status_text(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        423 => "Locked",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => match status / 100 {
+            1 => "Informational",
+            2 => "Success",
+            3 => "Redirection",
+            4 => "Client Error",
+            _ => "Server Error",
+        },
+    }
+}
+
+/// A `Set-Cookie` value. Cookies default to `Path=/; HttpOnly; SameSite=Lax`,
+/// so browsers leave them off cross-site POSTs and subresource requests.
+pub struct Cookie {
+    name: String,
+    value: String,
+    max_age: Option<i64>,
+    secure: bool,
+    http_only: bool,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            max_age: None,
+            secure: false,
+            http_only: true,
+        }
+    }
+
+    /// A cookie that tells the browser to drop `name` immediately
+    pub fn removal(name: &str) -> Self {
+        Cookie::new(name, "").max_age(0)
+    }
+
+    pub fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Only send the cookie over HTTPS
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Let page scripts read the cookie, e.g. to echo a CSRF token
+    pub fn readable_by_scripts(mut self) -> Self {
+        self.http_only = false;
+        self
+    }
+
+    fn header_value(&self) -> String {
+        let mut value = format!("{}={}; Path=/; SameSite=Lax", self.name, self.value);
+        if self.http_only {
+            value.push_str("; HttpOnly");
+        }
+        if let Some(max_age) = self.max_age {
+            value.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if self.secure {
+            value.push_str("; Secure");
+        }
+        value
+    }
+}
+
+enum Body {
+    Bytes(Vec<u8>),
+    /// reader plus its length if known; unknown lengths are sent chunked
+    Stream(Box<dyn AsyncRead + Send + Unpin>, Option<u64>),
+}
+
+/// An HTTP response under construction.
+/// `Content-Length`/`Transfer-Encoding` and `Connection` are filled in by `send`.
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// Adds a header; repeated names are sent as separate lines
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn cookie(self, cookie: Cookie) -> Self {
+        let value = cookie.header_value();
+        self.header("Set-Cookie", &value)
+    }
+
+    pub fn body(mut self, body: &[u8]) -> Self {
+        self.body = Body::Bytes(body.to_vec());
+        self
+    }
+
+    /// Streams the body from `reader` instead of buffering it
+    pub fn stream<R>(mut self, reader: R, len: Option<u64>) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        self.body = Body::Stream(Box::new(reader), len);
+        self
+    }
+
+    pub async fn send(self, conn: &mut Conn) -> std::io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, status_text(self.status));
+        // first, so cookies the handler sets itself (a new session on login,
+        // a removal on logout) win over a refresh of the old one
+        for cookie in std::mem::take(&mut conn.cookies) {
+            head.push_str(&format!("Set-Cookie: {}\r\n", cookie.header_value()));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(id) = &conn.request_id {
+            head.push_str(&format!("X-Request-Id: {}\r\n", id));
+        }
+        conn.status = Some(self.status);
+
+        match self.body {
+            Body::Bytes(bytes) => {
+                // 204/304 carry no body and must not claim a length for one
+                if !matches!(self.status, 204 | 304) {
+                    head.push_str(&format!("Content-Length: {}\r\n", bytes.len()));
+                }
+                head.push_str(&format!("Connection: {}\r\n\r\n", conn.connection_header()));
+                conn.write_all(head.as_bytes()).await?;
+                if !conn.head_only {
+                    conn.write_all(&bytes).await?;
+                }
+            }
+            Body::Stream(mut reader, Some(len)) => {
+                head.push_str(&format!("Content-Length: {}\r\n", len));
+                head.push_str(&format!("Connection: {}\r\n\r\n", conn.connection_header()));
+                conn.write_all(head.as_bytes()).await?;
+                if conn.head_only {
+                    return conn.flush().await;
+                }
+                let copied = tokio::io::copy(&mut (&mut reader).take(len), conn).await?;
+                if copied < len {
+                    // the peer expects `len` bytes; bail rather than desync the connection
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "streamed body shorter than its Content-Length",
+                    ));
+                }
+            }
+            Body::Stream(mut reader, None) => {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+                head.push_str(&format!("Connection: {}\r\n\r\n", conn.connection_header()));
+                conn.write_all(head.as_bytes()).await?;
+                if conn.head_only {
+                    return conn.flush().await;
+                }
+
+                let mut chunk = [0u8; 8192];
+                loop {
+                    let n = reader.read(&mut chunk).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    conn.write_all(format!("{:x}\r\n", n).as_bytes()).await?;
+                    conn.write_all(&chunk[..n]).await?;
+                    conn.write_all(b"\r\n").await?;
+                }
+                conn.write_all(b"0\r\n\r\n").await?;
+            }
+        }
+
+        conn.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `response` over an in-memory pipe and returns the result and
+    /// everything the client received
+    async fn send_and_capture(response: Response, keep_alive: bool) -> (std::io::Result<()>, String) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut conn = Conn::new(server, false);
+        conn.keep_alive = keep_alive;
+        conn.request_id = Some("abc123".to_string());
+        let result = response.send(&mut conn).await;
+        drop(conn);
+
+        let mut received = Vec::new();
+        let mut client = client;
+        client.read_to_end(&mut received).await.unwrap();
+        (result, String::from_utf8(received).unwrap())
+    }
+
+    #[tokio::test]
+    async fn bytes_body_with_headers_and_length() {
+        let response = Response::new(200)
+            .header("Content-Type", "text/plain")
+            .cookie(Cookie::new("session", "tok").max_age(60).secure(true))
+            .body(b"hello");
+        let (result, received) = send_and_capture(response, true).await;
+        result.unwrap();
+        assert_eq!(
+            received,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain\r\n\
+             Set-Cookie: session=tok; Path=/; SameSite=Lax; HttpOnly; Max-Age=60; Secure\r\n\
+             X-Request-Id: abc123\r\n\
+             Content-Length: 5\r\n\
+             Connection: keep-alive\r\n\
+             \r\n\
+             hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn head_responses_keep_the_headers_and_drop_the_body() {
+        for response in [
+            Response::new(200).body(b"hello"),
+            Response::new(200).stream(&b"hello"[..], Some(5)),
+        ] {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let mut conn = Conn::new(server, false);
+            conn.head_only = true;
+            response.send(&mut conn).await.unwrap();
+            drop(conn);
+
+            let mut received = String::new();
+            let mut client = client;
+            client.read_to_string(&mut received).await.unwrap();
+            assert_eq!(received, "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_cookies_come_before_the_responses_own() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut conn = Conn::new(server, false);
+        conn.cookies.push(Cookie::new("session", "old").max_age(60));
+        Response::new(200)
+            .cookie(Cookie::removal("session"))
+            .send(&mut conn)
+            .await
+            .unwrap();
+        assert!(conn.cookies.is_empty());
+        drop(conn);
+
+        let mut received = String::new();
+        let mut client = client;
+        client.read_to_string(&mut received).await.unwrap();
+        let refreshed = received.find("session=old;").unwrap();
+        let removed = received.find("session=;").unwrap();
+        assert!(refreshed < removed, "{}", received);
+    }
+
+    #[tokio::test]
+    async fn no_content_and_not_modified_have_no_length() {
+        for status in [204, 304] {
+            let (result, received) = send_and_capture(Response::new(status), false).await;
+            result.unwrap();
+            assert!(received.starts_with(&format!("HTTP/1.1 {} {}\r\n", status, status_text(status))));
+            assert!(!received.contains("Content-Length"), "{}", received);
+            assert!(received.ends_with("Connection: close\r\n\r\n"), "{}", received);
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_of_known_length() {
+        let response = Response::new(200).stream(&b"0123456789"[..], Some(10));
+        let (result, received) = send_and_capture(response, false).await;
+        result.unwrap();
+        assert!(received.contains("Content-Length: 10\r\n"));
+        assert!(received.ends_with("\r\n\r\n0123456789"));
+    }
+
+    #[tokio::test]
+    async fn stream_of_unknown_length_is_chunked() {
+        let body = vec![b'x'; 8192 + 3];
+        let response = Response::new(200).stream(std::io::Cursor::new(body), None);
+        let (result, received) = send_and_capture(response, false).await;
+        result.unwrap();
+        assert!(received.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!received.contains("Content-Length"));
+
+        let body = received.split_once("\r\n\r\n").unwrap().1;
+        let expected = format!("2000\r\n{}\r\n3\r\nxxx\r\n0\r\n\r\n", "x".repeat(8192));
+        assert_eq!(body, expected);
+    }
+
+    #[tokio::test]
+    async fn short_stream_is_an_error() {
+        let response = Response::new(200).stream(&b"abc"[..], Some(10));
+        let (result, received) = send_and_capture(response, false).await;
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(received.contains("Content-Length: 10\r\n"));
+    }
+}
@@ -0,0 +1,305 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use sqlx::SqlitePool;
+
+use crate::auth::PasswordPolicy;
+use crate::config::Config;
+use crate::error::ApiResult;
+use crate::mailer::Mailer;
+use crate::metrics::Metrics;
+use crate::session::SessionStore;
+use crate::throttle::Throttle;
+use crate::http::{Conn, Request};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Everything a route handler gets to work with for one request
+pub struct Ctx<'a> {
+    pub stream: &'a mut Conn,
+    pub pool: SqlitePool,
+    pub config: &'a Config,
+    pub throttle: &'a Throttle,
+    pub sessions: &'a SessionStore,
+    pub passwords: &'a PasswordPolicy,
+    pub mailer: &'a Arc<dyn Mailer>,
+    pub metrics: &'a Metrics,
+    pub request: &'a Request,
+    pub params: Params,
+    /// (username, role) if the request carried a valid session cookie
+    pub session: Option<(String, String)>,
+}
+
+impl Ctx<'_> {
+    pub fn has_role(&self, role: &str) -> bool {
+        matches!(&self.session, Some((_, r)) if r == role)
+    }
+
+    /// Username of the logged-in user. Always set on role-guarded routes,
+    /// empty on public routes hit without a session.
+    pub fn username(&self) -> &str {
+        self.session.as_ref().map(|(u, _)| u.as_str()).unwrap_or("")
+    }
+}
+
+pub type Handler = for<'a> fn(Ctx<'a>) -> BoxFuture<'a, ApiResult>;
+
+/// Values captured from `{name}` segments of the matched route
+#[derive(Default)]
+pub struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    /// Parses the named parameter, None if it is missing or doesn't parse as T
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.0
+            .iter()
+            .find(|(k, _)| *k == name)
+            .and_then(|(_, v)| v.parse().ok())
+    }
+}
+
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+/// Who may call a route
+#[derive(Clone, Copy)]
+pub enum Access {
+    Public,
+    /// any logged-in user
+    Session,
+    /// logged-in users with this role
+    Role(&'static str),
+}
+
+struct Route {
+    method: &'static str,
+    pattern: &'static str,
+    segments: Vec<Segment>,
+    handler: Handler,
+    access: Access,
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> Option<Params> {
+        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(lit) if *lit == part => {}
+                Segment::Param(name) if !part.is_empty() => params.push((*name, part.to_string())),
+                _ => return None,
+            }
+        }
+        Some(Params(params))
+    }
+}
+
+/// Outcome of looking up a method + path
+pub enum Resolved<'r> {
+    /// handler, captured params, who may call it and the route's pattern
+    Found(&'r Handler, Params, Access, &'static str),
+    MethodNotAllowed(Vec<&'static str>),
+    NotFound,
+}
+
+/// Maps `METHOD /path/{param}` patterns to handlers.
+/// Routes are tried in registration order; the first match wins.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    pub fn route(&mut self, method: &'static str, pattern: &'static str, handler: Handler) -> &mut Self {
+        self.add(method, pattern, handler, Access::Public)
+    }
+
+    /// Routes registered through the returned scope require a session with `role`
+    pub fn role(&mut self, role: &'static str) -> Scope<'_> {
+        Scope { router: self, access: Access::Role(role) }
+    }
+
+    /// Routes registered through the returned scope require any valid session
+    pub fn logged_in(&mut self) -> Scope<'_> {
+        Scope { router: self, access: Access::Session }
+    }
+
+    fn add(
+        &mut self,
+        method: &'static str,
+        pattern: &'static str,
+        handler: Handler,
+        access: Access,
+    ) -> &mut Self {
+        let segments = pattern
+            .trim_start_matches('/')
+            .split('/')
+            .map(|s| match s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => Segment::Param(name),
+                None => Segment::Literal(s),
+            })
+            .collect();
+
+        self.routes.push(Route {
+            method,
+            pattern,
+            segments,
+            handler,
+            access,
+        });
+        self
+    }
+
+    pub fn get(&mut self, pattern: &'static str, handler: Handler) -> &mut Self {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post(&mut self, pattern: &'static str, handler: Handler) -> &mut Self {
+        self.route("POST", pattern, handler)
+    }
+
+    /// HEAD is answered by the GET route; the body is dropped when sending
+    pub fn resolve(&self, method: &str, path: &str) -> Resolved<'_> {
+        let mut allowed = Vec::new();
+
+        for route in &self.routes {
+            if let Some(params) = route.matches(path) {
+                if route.method == method || (method == "HEAD" && route.method == "GET") {
+                    return Resolved::Found(&route.handler, params, route.access, route.pattern);
+                }
+                let methods: &[&'static str] = if route.method == "GET" { &["GET", "HEAD"] } else { &[route.method] };
+                for method in methods {
+                    if !allowed.contains(method) {
+                        allowed.push(*method);
+                    }
+                }
+            }
+        }
+
+        if allowed.is_empty() {
+            Resolved::NotFound
+        } else {
+            Resolved::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+/// Registers routes that all share the same access rule
+pub struct Scope<'r> {
+    router: &'r mut Router,
+    access: Access,
+}
+
+impl Scope<'_> {
+    pub fn route(&mut self, method: &'static str, pattern: &'static str, handler: Handler) -> &mut Self {
+        self.router.add(method, pattern, handler, self.access);
+        self
+    }
+
+    pub fn get(&mut self, pattern: &'static str, handler: Handler) -> &mut Self {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post(&mut self, pattern: &'static str, handler: Handler) -> &mut Self {
+        self.route("POST", pattern, handler)
+    }
+
+    pub fn put(&mut self, pattern: &'static str, handler: Handler) -> &mut Self {
+        self.route("PUT", pattern, handler)
+    }
+
+    pub fn delete(&mut self, pattern: &'static str, handler: Handler) -> &mut Self {
+        self.route("DELETE", pattern, handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop(_: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Routes told apart by their access rule where they share a path
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/books", noop);
+        router.logged_in().post("/books", noop);
+        router
+            .role("admin")
+            .get("/admin/api/users/{id}/sessions", noop)
+            .delete("/admin/api/users/{id}/sessions", noop)
+            .put("/admin/api/books/{bookid}/copies/{n}", noop);
+        router
+    }
+
+    fn found(router: &Router, method: &str, path: &str) -> (Access, Params, &'static str) {
+        match router.resolve(method, path) {
+            Resolved::Found(_, params, access, pattern) => (access, params, pattern),
+            _ => panic!("{} {} did not resolve", method, path),
+        }
+    }
+
+    #[test]
+    fn path_params_are_captured() {
+        let router = router();
+        let (access, params, pattern) = found(&router, "DELETE", "/admin/api/users/42/sessions");
+        assert!(matches!(access, Access::Role("admin")));
+        assert_eq!(pattern, "/admin/api/users/{id}/sessions");
+        assert_eq!(params.get::<i64>("id"), Some(42));
+        assert_eq!(params.get::<i64>("other"), None);
+
+        let (_, params, _) = found(&router, "PUT", "/admin/api/books/7/copies/3");
+        assert_eq!((params.get::<i64>("bookid"), params.get::<u32>("n")), (Some(7), Some(3)));
+
+        // captured as text; a value that doesn't parse is just None
+        let (_, params, _) = found(&router, "GET", "/admin/api/users/abc/sessions");
+        assert_eq!(params.get::<i64>("id"), None);
+        assert_eq!(params.get::<String>("id").as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn unknown_paths_are_404_and_wrong_methods_405() {
+        let router = router();
+        for path in ["/nope", "/books/1", "/admin/api/users//sessions", "/admin/api/users/1"] {
+            assert!(matches!(router.resolve("GET", path), Resolved::NotFound), "{}", path);
+        }
+        match router.resolve("PATCH", "/books") {
+            Resolved::MethodNotAllowed(allowed) => assert_eq!(allowed, ["GET", "HEAD", "POST"]),
+            _ => panic!("expected 405"),
+        }
+        match router.resolve("POST", "/admin/api/users/1/sessions") {
+            Resolved::MethodNotAllowed(allowed) => assert_eq!(allowed, ["GET", "HEAD", "DELETE"]),
+            _ => panic!("expected 405"),
+        }
+        match router.resolve("HEAD", "/admin/api/books/1/copies/2") {
+            Resolved::MethodNotAllowed(allowed) => assert_eq!(allowed, ["PUT"]),
+            _ => panic!("expected 405"),
+        }
+    }
+
+    #[test]
+    fn head_resolves_to_the_get_route() {
+        let router = router();
+        let (access, _, pattern) = found(&router, "HEAD", "/books");
+        assert!(matches!(access, Access::Public));
+        assert_eq!(pattern, "/books");
+        let (access, ..) = found(&router, "POST", "/books");
+        assert!(matches!(access, Access::Session));
+        let (_, params, _) = found(&router, "HEAD", "/admin/api/users/5/sessions");
+        assert_eq!(params.get::<i64>("id"), Some(5));
+    }
+}
@@ -0,0 +1,338 @@
+use std::fs;
+use std::io::Write;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::SqlitePool;
+
+use crate::auth::generate_session_token;
+use crate::config::SessionConfig;
+use crate::http::{Conn, Request};
+use crate::models::SessionInfo;
+use crate::response::Cookie;
+
+/// Name of the cookie that carries the session token
+pub const COOKIE: &str = "session";
+
+/// Script-readable cookie holding the session's CSRF token
+pub const CSRF_COOKIE: &str = "csrf";
+
+/// Header that state-changing requests echo the CSRF token back in
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// `last_seen_at` is only rewritten this often, so browsing doesn't cost a
+/// database write per request
+const TOUCH_INTERVAL: &str = "-60 seconds";
+
+/// Longest user agent kept for the session list
+const MAX_USER_AGENT: usize = 256;
+
+/// Where a new session comes from, shown back to the user in the session list
+pub struct Origin {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// "remember me": the longer idle lifetime and a persistent cookie
+    pub remember: bool,
+}
+
+impl Origin {
+    pub fn new(stream: &Conn, request: &Request, remember: bool) -> Self {
+        Origin {
+            ip: stream.peer_ip.map(|ip| ip.to_string()),
+            user_agent: request
+                .header("user-agent")
+                .map(|ua| ua.chars().take(MAX_USER_AGENT).collect()),
+            remember,
+        }
+    }
+}
+
+/// Who a live session belongs to
+pub struct SessionUser {
+    pub id: i64,
+    pub username: String,
+    pub role: String,
+    /// the user has confirmed a TOTP authenticator
+    pub two_factor: bool,
+    /// "remember me" cookies carrying the slid expiry, when it moved; the
+    /// browser would otherwise drop them `remember_days` after login
+    pub refreshed: Option<Issued>,
+}
+
+/// A freshly created session
+pub struct Issued {
+    pub token: String,
+    pub csrf_token: String,
+    /// cookie Max-Age; None keeps the cookie until the browser closes
+    pub max_age: Option<i64>,
+}
+
+impl Issued {
+    /// The session and CSRF cookies to set on the response
+    pub fn cookies(&self, secure: bool) -> [Cookie; 2] {
+        let lifetime = |cookie: Cookie| match self.max_age {
+            Some(max_age) => cookie.max_age(max_age),
+            None => cookie,
+        };
+        [
+            lifetime(Cookie::new(COOKIE, &self.token).secure(secure)),
+            lifetime(Cookie::new(CSRF_COOKIE, &self.csrf_token).secure(secure).readable_by_scripts()),
+        ]
+    }
+}
+
+/// Cookies that clear both session cookies
+pub fn removal_cookies(secure: bool) -> [Cookie; 2] {
+    [
+        Cookie::removal(COOKIE).secure(secure),
+        Cookie::removal(CSRF_COOKIE).secure(secure).readable_by_scripts(),
+    ]
+}
+
+/// Creates, looks up and ends sessions. Only an HMAC of each token is
+/// stored, keyed with a secret kept outside the database, so a copy of the
+/// database alone can't be turned back into live cookies.
+pub struct SessionStore {
+    key: Vec<u8>,
+    config: SessionConfig,
+}
+
+impl SessionStore {
+    /// Reads the key from `config.key_file`, generating one on first start
+    pub fn load(config: &SessionConfig) -> anyhow::Result<Self> {
+        let path = &config.key_file;
+        let key = match fs::read_to_string(path) {
+            Ok(contents) => contents.trim().to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = generate_session_token();
+                write_private(path, &key)
+                    .map_err(|e| anyhow::anyhow!("cannot create session key {}: {}", path, e))?;
+                tracing::info!("Created session key {}", path);
+                key
+            }
+            Err(e) => anyhow::bail!("cannot read session key {}: {}", path, e),
+        };
+        if key.len() < 32 {
+            anyhow::bail!("session key {} is too short, use at least 32 characters", path);
+        }
+
+        Ok(SessionStore {
+            key: key.into_bytes(),
+            config: config.clone(),
+        })
+    }
+
+    /// The form a token is stored and looked up in
+    pub fn hash(&self, token: &str) -> String {
+        self.mac(&[token.as_bytes()])
+    }
+
+    /// CSRF token of the session with `token`. Derived rather than stored, so
+    /// it lives and dies with the session and can't be guessed without the key.
+    pub fn csrf_token(&self, token: &str) -> String {
+        self.mac(&[b"csrf:", token.as_bytes()])
+    }
+
+    /// Whether the request echoes its session's CSRF token in the header
+    pub fn verify_csrf(&self, request: &Request) -> bool {
+        match (request.cookie(COOKIE), request.header(CSRF_HEADER)) {
+            (Some(token), Some(sent)) => constant_time_eq(self.csrf_token(&token).as_bytes(), sent.as_bytes()),
+            _ => false,
+        }
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key length");
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Hash of the request's session cookie, empty if there is none
+    pub fn current_hash(&self, request: &Request) -> String {
+        request
+            .cookie(COOKIE)
+            .map(|token| self.hash(&token))
+            .unwrap_or_default()
+    }
+
+    /// Creates a session row in the DB and returns its token
+    pub async fn create(&self, pool: &SqlitePool, user_id: i64, origin: &Origin) -> anyhow::Result<Issued> {
+        let token = generate_session_token();
+        let lifetime = self.config.lifetime_secs(origin.remember);
+
+        sqlx::query(
+            "
+            INSERT INTO sessions
+                (token_hash, user_id, created_at, last_seen_at, expires_at, remember, ip, user_agent)
+            VALUES (?, ?, datetime('now'), datetime('now'), datetime('now', ?), ?, ?, ?)
+            ",
+        )
+        .bind(self.hash(&token))
+        .bind(user_id)
+        .bind(format!("+{} seconds", lifetime))
+        .bind(origin.remember)
+        .bind(&origin.ip)
+        .bind(&origin.user_agent)
+        .execute(pool)
+        .await?;
+
+        Ok(Issued {
+            csrf_token: self.csrf_token(&token),
+            token,
+            max_age: origin.remember.then_some(lifetime),
+        })
+    }
+
+    /// Looks up the session cookie token in the DB.
+    /// Returns the user if a live session exists and slides its expiry
+    /// forward (and with it a remembered cookie's Max-Age), else None. The
+    /// role is read from the user row, so a change applies immediately.
+    /// Expired rows are left to the sweeper. A failed lookup is an error,
+    /// not a logout.
+    pub async fn resolve(&self, pool: &SqlitePool, request: &Request) -> anyhow::Result<Option<SessionUser>> {
+        let Some(token) = request.cookie(COOKIE) else {
+            return Ok(None);
+        };
+
+        let row: Option<(i64, i64, String, String, bool, bool, bool)> = sqlx::query_as(
+            "
+            SELECT s.id, u.id, u.username, u.role, t.enabled_at IS NOT NULL, s.remember,
+                   s.last_seen_at <= datetime('now', ?)
+            FROM sessions s
+            JOIN users u ON u.id = s.user_id
+            LEFT JOIN user_totp t ON t.user_id = u.id
+            WHERE s.token_hash = ? AND s.expires_at > datetime('now')
+            ",
+        )
+        .bind(TOUCH_INTERVAL)
+        .bind(self.hash(&token))
+        .fetch_optional(pool)
+        .await?;
+        let Some((id, user_id, username, role, two_factor, remember, stale)) = row else {
+            return Ok(None);
+        };
+
+        let mut refreshed = None;
+        if stale {
+            let lifetime = self.config.lifetime_secs(remember);
+            let touched = sqlx::query(
+                "UPDATE sessions SET last_seen_at = datetime('now'), expires_at = datetime('now', ?) WHERE id = ?",
+            )
+            .bind(format!("+{} seconds", lifetime))
+            .bind(id)
+            .execute(pool)
+            .await;
+            // browser-session cookies have no expiry to move
+            if touched.is_ok() && remember {
+                refreshed = Some(Issued {
+                    csrf_token: self.csrf_token(&token),
+                    token,
+                    max_age: Some(lifetime),
+                });
+            }
+        }
+
+        Ok(Some(SessionUser {
+            id: user_id,
+            username,
+            role,
+            two_factor,
+            refreshed,
+        }))
+    }
+
+    /// Whether the request's session was created with "remember me"
+    pub async fn remembered(&self, pool: &SqlitePool, request: &Request) -> bool {
+        sqlx::query_scalar::<_, bool>("SELECT remember FROM sessions WHERE token_hash = ?")
+            .bind(self.current_hash(request))
+            .fetch_optional(pool)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(false)
+    }
+
+    /// Deletes the session named by the request's cookie, if any
+    pub async fn destroy(&self, pool: &SqlitePool, request: &Request) {
+        if let Some(token) = request.cookie(COOKIE) {
+            let _ = sqlx::query("DELETE FROM sessions WHERE token_hash = ?")
+                .bind(self.hash(&token))
+                .execute(pool)
+                .await;
+        }
+    }
+
+    /// Live sessions of `user_id`, most recently used first. The request's
+    /// own session is marked `current`.
+    pub async fn list(
+        &self,
+        pool: &SqlitePool,
+        user_id: i64,
+        request: &Request,
+    ) -> Result<Vec<SessionInfo>, sqlx::Error> {
+        sqlx::query_as::<_, SessionInfo>(
+            "
+            SELECT id, created_at, last_seen_at, expires_at, remember, ip, user_agent, token_hash = ? AS current
+            FROM sessions
+            WHERE user_id = ? AND expires_at > datetime('now')
+            ORDER BY last_seen_at DESC
+            ",
+        )
+        .bind(self.current_hash(request))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+    }
+}
+
+/// Compares without bailing at the first difference, so response timing
+/// doesn't reveal how much of a guess was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Writes a new file readable only by the owner (on unix)
+fn write_private(path: &str, contents: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.write_all(b"\n")
+}
+
+/// Deletes every expired session and abandoned 2FA login, returns how many
+/// sessions went
+pub async fn sweep(pool: &SqlitePool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM sessions WHERE expires_at <= datetime('now')")
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM login_challenges WHERE expires_at <= datetime('now')")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Sweeps expired sessions every `interval` until the task is aborted
+pub async fn run_sweeper(pool: SqlitePool, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match sweep(&pool).await {
+            Ok(0) => {}
+            Ok(n) => tracing::info!("Swept {} expired session(s)", n),
+            Err(e) => tracing::error!(error = ?e, "Session sweep failed"),
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::http::{Conn, Request};
+use crate::response::Response;
+
+/// Directory every static asset is served from
+pub const STATIC_ROOT: &str = "static";
+
+/// Files smaller than this aren't worth compressing
+const GZIP_MIN_BYTES: u64 = 1024;
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Maps a request path like `/css/site.css` to a file under `STATIC_ROOT`.
+/// Only plain segments of `[A-Za-z0-9._-]` are accepted, none may start with
+/// a dot, so `..`, hidden files, encoded separators and empty segments are
+/// all rejected before touching the filesystem.
+pub fn resolve_path(request_path: &str) -> Option<PathBuf> {
+    let rel = request_path.strip_prefix('/')?;
+    if rel.is_empty() {
+        return None;
+    }
+
+    let mut path = PathBuf::from(STATIC_ROOT);
+    for segment in rel.split('/') {
+        let valid = !segment.is_empty()
+            && !segment.starts_with('.')
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b'_');
+        if !valid {
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+/// Content-Type by file extension
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("application/xml")
+        || content_type.starts_with("image/svg+xml")
+}
+
+/// Serves a file if it exists. Returns Ok(false) when there is no such
+/// regular file so the caller can fall through to a 404.
+pub async fn serve(stream: &mut Conn, request: &Request, path: &Path) -> anyhow::Result<bool> {
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(m) if m.is_file() => m,
+        _ => return Ok(false),
+    };
+
+    let len = metadata.len();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+
+    let etag = format!("W/\"{:x}-{:x}\"", len, modified);
+    let last_modified = DateTime::<Utc>::from_timestamp(modified, 0)
+        .unwrap_or_default()
+        .format(HTTP_DATE)
+        .to_string();
+
+    let content_type = content_type(path);
+    let cache_control = if content_type.starts_with("text/html") {
+        // pages must revalidate so a logout/login is always reflected
+        "no-cache"
+    } else {
+        "public, max-age=3600"
+    };
+
+    // the representation depends on Accept-Encoding, so every response for
+    // the file, 304s included, has to say so for caches to key on it
+    let compressible = is_compressible(content_type) && len >= GZIP_MIN_BYTES;
+
+    if not_modified(request, &etag, modified) {
+        let response = Response::new(304)
+            .header("ETag", &etag)
+            .header("Last-Modified", &last_modified)
+            .header("Cache-Control", cache_control);
+        let response = if compressible {
+            response.header("Vary", "Accept-Encoding")
+        } else {
+            response
+        };
+        response.send(stream).await?;
+        return Ok(true);
+    }
+
+    let response = Response::new(200)
+        .header("Content-Type", content_type)
+        .header("ETag", &etag)
+        .header("Last-Modified", &last_modified)
+        .header("Cache-Control", cache_control);
+
+    if compressible && accepts_gzip(request) {
+        let raw = tokio::fs::read(path).await?;
+        let gzipped = tokio::task::spawn_blocking(move || -> std::io::Result<Vec<u8>> {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&raw)?;
+            encoder.finish()
+        })
+        .await??;
+
+        response
+            .header("Content-Encoding", "gzip")
+            .header("Vary", "Accept-Encoding")
+            .body(&gzipped)
+            .send(stream)
+            .await?;
+    } else {
+        let file = tokio::fs::File::open(path).await?;
+        let response = if compressible {
+            response.header("Vary", "Accept-Encoding")
+        } else {
+            response
+        };
+        response.stream(file, Some(len)).send(stream).await?;
+    }
+
+    Ok(true)
+}
+
+/// If-None-Match wins over If-Modified-Since when both are present
+fn not_modified(request: &Request, etag: &str, modified: i64) -> bool {
+    if let Some(tags) = request.header("if-none-match") {
+        return tags
+            .split(',')
+            .map(|t| t.trim())
+            .any(|t| t == "*" || t == etag || t.trim_start_matches("W/") == etag.trim_start_matches("W/"));
+    }
+
+    request
+        .header("if-modified-since")
+        .and_then(|since| NaiveDateTime::parse_from_str(since, HTTP_DATE).ok())
+        .map(|since| modified <= since.and_utc().timestamp())
+        .unwrap_or(false)
+}
+
+fn accepts_gzip(request: &Request) -> bool {
+    request
+        .header("accept-encoding")
+        .map(|v| {
+            v.split(',').any(|enc| {
+                let mut parts = enc.trim().split(';');
+                let name = parts.next().unwrap_or("").trim();
+                // "gzip;q=0" means explicitly not acceptable
+                let refused = parts.any(|p| matches!(p.trim(), "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+                (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+            })
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    /// A static file with `contents` that is removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(extension: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("library-test-{:016x}.{}", rand::random::<u64>(), extension));
+            std::fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn get(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: "/file".to_string(),
+            query: String::new(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Serves `path` for `request` and splits what the client received
+    /// into the head and the body
+    async fn serve_and_capture(request: &Request, path: &Path) -> (String, Vec<u8>) {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut conn = Conn::new(server, false);
+        assert!(serve(&mut conn, request, path).await.unwrap());
+        drop(conn);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        let split = received.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(received[..split].to_vec()).unwrap();
+        (head, received[split + 4..].to_vec())
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines()
+            .filter_map(|line| line.split_once(": "))
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    #[test]
+    fn resolve_path_rejects_traversal_and_hidden_segments() {
+        assert_eq!(resolve_path("/css/site.css"), Some(Path::new(STATIC_ROOT).join("css").join("site.css")));
+
+        for bad in [
+            "/",
+            "",
+            "css/site.css",
+            "/../Cargo.toml",
+            "/css/../../secret",
+            "/..",
+            "/.env",
+            "/css/.hidden",
+            "/css//site.css",
+            "/css/",
+            "/%2e%2e/secret",
+            "/css%2fsite.css",
+            "/css\\..\\secret",
+        ] {
+            assert_eq!(resolve_path(bad), None, "{bad:?} should be rejected");
+        }
+    }
+
+    #[tokio::test]
+    async fn conditional_requests_get_304_with_the_same_vary() {
+        let file = TempFile::new("css", &[b'a'; 4096]);
+        let (head, _) = serve_and_capture(&get(&[]), &file.0).await;
+        assert!(head.starts_with("HTTP/1.1 200 "), "{head}");
+        let etag = header(&head, "ETag").unwrap().to_string();
+        let last_modified = header(&head, "Last-Modified").unwrap().to_string();
+        assert_eq!(header(&head, "Vary"), Some("Accept-Encoding"));
+
+        for conditional in [
+            get(&[("If-None-Match", &etag)]),
+            get(&[("If-None-Match", &format!("\"other\", {etag}"))]),
+            get(&[("If-Modified-Since", &last_modified)]),
+        ] {
+            let (head, body) = serve_and_capture(&conditional, &file.0).await;
+            assert!(head.starts_with("HTTP/1.1 304 "), "{head}");
+            assert_eq!(header(&head, "ETag"), Some(etag.as_str()));
+            assert_eq!(header(&head, "Vary"), Some("Accept-Encoding"));
+            assert!(body.is_empty());
+        }
+
+        // a stale validator gets the full file; If-None-Match wins over a
+        // matching If-Modified-Since
+        for stale in [
+            get(&[("If-None-Match", "W/\"0-0\"")]),
+            get(&[("If-None-Match", "W/\"0-0\""), ("If-Modified-Since", &last_modified)]),
+            get(&[("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")]),
+        ] {
+            let (head, body) = serve_and_capture(&stale, &file.0).await;
+            assert!(head.starts_with("HTTP/1.1 200 "), "{head}");
+            assert_eq!(body.len(), 4096);
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip_only_when_accepted_and_worth_it() {
+        let contents = b"body { color: red; }\n".repeat(100);
+        let file = TempFile::new("css", &contents);
+
+        let (head, body) = serve_and_capture(&get(&[("Accept-Encoding", "br, gzip")]), &file.0).await;
+        assert_eq!(header(&head, "Content-Encoding"), Some("gzip"));
+        assert_eq!(header(&head, "Vary"), Some("Accept-Encoding"));
+        assert_eq!(header(&head, "Content-Length"), Some(body.len().to_string().as_str()));
+        let mut decoded = Vec::new();
+        GzDecoder::new(body.as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, contents);
+
+        for refused in [&[][..], &[("Accept-Encoding", "gzip;q=0")], &[("Accept-Encoding", "br")]] {
+            let (head, body) = serve_and_capture(&get(refused), &file.0).await;
+            assert_eq!(header(&head, "Content-Encoding"), None, "{refused:?}");
+            assert_eq!(header(&head, "Vary"), Some("Accept-Encoding"));
+            assert_eq!(body, contents);
+        }
+
+        // small files and binary types go out as they are, with no Vary
+        let small = TempFile::new("css", b"p {}");
+        let image = TempFile::new("png", &[0u8; 4096]);
+        for path in [&small.0, &image.0] {
+            let (head, _) = serve_and_capture(&get(&[("Accept-Encoding", "gzip")]), path).await;
+            assert_eq!(header(&head, "Content-Encoding"), None);
+            assert_eq!(header(&head, "Vary"), None);
+        }
+    }
+}
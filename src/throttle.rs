@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Entries untouched for this long are forgotten
+const FORGET_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// How often `run_pruner` drops forgotten entries
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Attempts {
+    failures: u32,
+    blocked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+/// In-memory failure counter with exponential backoff, keyed by strings like
+/// `ip:1.2.3.4` or `user:alice`. The first `free_attempts` failures cost
+/// nothing; each one after that doubles the wait, up to `max_backoff`.
+pub struct Throttle {
+    free_attempts: u32,
+    base_backoff: Duration,
+    max_backoff: Duration,
+    entries: Mutex<HashMap<String, Attempts>>,
+}
+
+impl Throttle {
+    pub fn new(free_attempts: u32, max_backoff: Duration) -> Self {
+        Throttle {
+            free_attempts,
+            base_backoff: Duration::from_secs(1),
+            max_backoff,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Err(wait) if any of `keys` is still backing off; otherwise counts the
+    /// attempt as a failure against every key. Checking and counting happen
+    /// under one lock, so parallel requests can't all get in before the first
+    /// failure is recorded. Give the attempt back with `refund` if it succeeds.
+    pub fn reserve(&self, keys: &[&str]) -> Result<(), Duration> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        let wait = keys
+            .iter()
+            .filter_map(|key| entries.get(*key).and_then(|a| a.blocked_until))
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+
+        for key in keys {
+            let attempts = entries.entry(key.to_string()).or_insert(Attempts {
+                failures: 0,
+                blocked_until: None,
+                last_failure: now,
+            });
+            if now.duration_since(attempts.last_failure) >= FORGET_AFTER {
+                attempts.failures = 0;
+            }
+            attempts.failures += 1;
+            attempts.last_failure = now;
+
+            if attempts.failures > self.free_attempts {
+                let exponent = (attempts.failures - self.free_attempts - 1).min(20);
+                let backoff = (self.base_backoff * 2u32.pow(exponent)).min(self.max_backoff);
+                attempts.blocked_until = Some(now + backoff);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes back one attempt counted by `reserve` that turned out to succeed
+    pub fn refund(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(attempts) = entries.get_mut(key) {
+            attempts.failures = attempts.failures.saturating_sub(1);
+            if attempts.failures <= self.free_attempts {
+                attempts.blocked_until = None;
+            }
+        }
+    }
+
+    /// Forgets `key`, e.g. after a successful login or an admin unlock
+    pub fn clear(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// Drops entries that are no longer backing off and whose last failure
+    /// is old enough to be forgotten; returns how many went
+    pub fn prune(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let before = entries.len();
+        entries.retain(|_, a| {
+            a.blocked_until.is_some_and(|until| until > now) || now.duration_since(a.last_failure) < FORGET_AFTER
+        });
+        before - entries.len()
+    }
+
+    /// Prunes every `PRUNE_INTERVAL` until the task is aborted
+    pub async fn run_pruner(&self) {
+        let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let pruned = self.prune();
+            if pruned > 0 {
+                tracing::debug!("Pruned {} throttle entries", pruned);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn backs_off_after_the_free_attempts() {
+        let throttle = Throttle::new(2, Duration::from_secs(60));
+        assert!(throttle.reserve(&["ip:a", "user:alice"]).is_ok());
+        assert!(throttle.reserve(&["ip:a", "user:alice"]).is_ok());
+        // the third is still let through, and starts the backoff
+        assert!(throttle.reserve(&["ip:a", "user:alice"]).is_ok());
+        let wait = throttle.reserve(&["ip:b", "user:alice"]).unwrap_err();
+        assert!(wait <= Duration::from_secs(1));
+        // a blocked attempt counts against none of the keys
+        assert!(throttle.reserve(&["ip:b"]).is_ok());
+        assert_eq!(throttle.entries.lock().unwrap()["ip:b"].failures, 1);
+
+        throttle.clear("user:alice");
+        throttle.clear("ip:a");
+        assert!(throttle.reserve(&["ip:a", "user:alice"]).is_ok());
+    }
+
+    #[test]
+    fn refunded_attempts_lift_the_backoff() {
+        let throttle = Throttle::new(1, Duration::from_secs(60));
+        assert!(throttle.reserve(&["ip:a"]).is_ok());
+        assert!(throttle.reserve(&["ip:a"]).is_ok());
+        assert!(throttle.reserve(&["ip:a"]).is_err());
+        throttle.refund("ip:a");
+        assert!(throttle.reserve(&["ip:a"]).is_ok());
+    }
+
+    #[test]
+    fn parallel_attempts_cannot_all_get_in() {
+        let throttle = Arc::new(Throttle::new(3, Duration::from_secs(60)));
+        let threads: Vec<_> = (0..32)
+            .map(|_| {
+                let throttle = throttle.clone();
+                std::thread::spawn(move || throttle.reserve(&["user:alice"]).is_ok())
+            })
+            .collect();
+        let admitted = threads.into_iter().map(|t| t.join().unwrap()).filter(|ok| *ok).count();
+        // the free attempts plus the one that starts the backoff
+        assert_eq!(admitted, 4);
+    }
+
+    #[test]
+    fn prune_keeps_recent_and_blocked_entries() {
+        let throttle = Throttle::new(0, Duration::from_secs(3600));
+        assert!(throttle.reserve(&["recent"]).is_ok());
+        {
+            let mut entries = throttle.entries.lock().unwrap();
+            let long_ago = Instant::now() - FORGET_AFTER - Duration::from_secs(1);
+            entries.insert(
+                "stale".to_string(),
+                Attempts { failures: 3, blocked_until: None, last_failure: long_ago },
+            );
+            entries.insert(
+                "blocked".to_string(),
+                Attempts {
+                    failures: 9,
+                    blocked_until: Some(Instant::now() + Duration::from_secs(60)),
+                    last_failure: long_ago,
+                },
+            );
+        }
+        assert_eq!(throttle.prune(), 1);
+        let entries = throttle.entries.lock().unwrap();
+        assert!(entries.contains_key("recent") && entries.contains_key("blocked"));
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+use crate::http::{Conn, Limits, Timeouts};
+use crate::response::Response;
+
+/// Builds an acceptor from PEM files (certificate chain + PKCS#8/PKCS#1/SEC1 key)
+pub fn load_acceptor(settings: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let mut cert_reader = BufReader::new(
+        File::open(&settings.cert_path)
+            .map_err(|e| anyhow::anyhow!("cannot open TLS cert {}: {}", settings.cert_path, e))?,
+    );
+    let certs = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", settings.cert_path);
+    }
+
+    let mut key_reader = BufReader::new(
+        File::open(&settings.key_path)
+            .map_err(|e| anyhow::anyhow!("cannot open TLS key {}: {}", settings.key_path, e))?,
+    );
+    let key = rustls_pemfile::private_key(&mut key_reader)?
+        .ok_or_else(|| anyhow::anyhow!("no private key found in {}", settings.key_path))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Answers every plain-HTTP request with a 301 to the same path on https.
+/// Its connections take permits from the same `limiter` as the main listener.
+pub async fn run_redirect_listener(
+    addr: String,
+    https_port: u16,
+    limits: Limits,
+    timeouts: Timeouts,
+    limiter: Arc<Semaphore>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    tracing::info!("Redirecting http://{} to https on port {}", addr, https_port);
+
+    loop {
+        let (stream, permit) = match crate::accept_with_permit(&listener, &limiter).await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!(error = ?e, "Redirect listener accept failed");
+                tokio::time::sleep(crate::ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let mut conn = Conn::new(stream, false);
+            conn.write_timeout = timeouts.write;
+            let request = match tokio::time::timeout(timeouts.read, conn.next_request(&limits)).await {
+                Ok(Ok(Some(request))) => request,
+                _ => return,
+            };
+
+            // keep the hostname the client used, swap in the https port
+            let host = request.header("host").unwrap_or("localhost");
+            let hostname = match host.rsplit_once(':') {
+                Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+                _ => host,
+            };
+            let authority = if https_port == 443 {
+                hostname.to_string()
+            } else {
+                format!("{}:{}", hostname, https_port)
+            };
+            let target = if request.query.is_empty() {
+                request.path.clone()
+            } else {
+                format!("{}?{}", request.path, request.query)
+            };
+            let location = format!("https://{}{}", authority, target);
+
+            let _ = Response::new(301)
+                .header("Location", &location)
+                .send(&mut conn)
+                .await;
+        });
+    }
+}
@@ -0,0 +1,485 @@
+use rand::Rng;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::auth::{generate_session_token, hash_password, verify_password};
+use crate::config::Config;
+use crate::db;
+use crate::error::{ApiError, ApiResult};
+use crate::http::{Conn, Request};
+use crate::metrics::{LoginResult, Metrics};
+use crate::response::{Cookie, Response};
+use crate::router::{BoxFuture, Ctx, Router};
+use crate::session::{self, SessionStore};
+use crate::throttle::Throttle;
+use crate::totp;
+use crate::{parse_form_urlencoded, send_json, send_response, send_too_many_requests};
+
+/// Cookie naming the login waiting for its second factor
+const CHALLENGE_COOKIE: &str = "login_challenge";
+
+/// How long the code prompt stays valid after the password was accepted
+const CHALLENGE_SECS: i64 = 300;
+
+/// Wrong codes allowed per challenge before the password is asked again
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+/// Recovery codes handed out when 2FA is enabled
+const RECOVERY_CODES: usize = 10;
+
+/// No 0/o or 1/l, so codes survive being written down
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+pub fn routes(router: &mut Router) {
+    router.post("/login/2fa", complete_login);
+
+    router
+        .logged_in()
+        .get("/account/api/2fa", status)
+        .post("/account/api/2fa/setup", setup)
+        .post("/account/api/2fa/enable", enable)
+        .post("/account/api/2fa/disable", disable);
+}
+
+/// Whether a user with `role` has to enrol before using their role's pages
+pub fn must_enroll(config: &Config, role: &str, enabled: bool) -> bool {
+    config.two_factor.required_for_admins && role == "admin" && !enabled
+}
+
+/// Whether the user has a confirmed authenticator
+pub async fn is_enabled(pool: &SqlitePool, user_id: i64) -> anyhow::Result<bool> {
+    let enabled = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM user_totp WHERE user_id = ? AND enabled_at IS NOT NULL",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(enabled > 0)
+}
+
+/// Parks a login that passed the password check and sends the browser to
+/// the code prompt. The challenge cookie only names the pending login; it
+/// grants nothing until a code is checked against it.
+pub async fn start_challenge(
+    stream: &mut Conn,
+    pool: &SqlitePool,
+    sessions: &SessionStore,
+    user_id: i64,
+    remember: bool,
+) -> anyhow::Result<()> {
+    let token = generate_session_token();
+    sqlx::query(
+        "INSERT INTO login_challenges (token_hash, user_id, remember, expires_at) VALUES (?, ?, ?, datetime('now', ?))",
+    )
+    .bind(sessions.hash(&token))
+    .bind(user_id)
+    .bind(remember)
+    .bind(format!("+{} seconds", CHALLENGE_SECS))
+    .execute(pool)
+    .await?;
+
+    Response::new(302)
+        .header("Location", "/2fa.html")
+        .cookie(Cookie::new(CHALLENGE_COOKIE, &token).max_age(CHALLENGE_SECS).secure(stream.is_secure()))
+        .send(stream)
+        .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct CodeInput {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordInput {
+    password: String,
+}
+
+//----------------------------------------------------------------------------------------------------------
+// routes
+
+fn complete_login(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let (config, throttle, sessions, metrics) = (ctx.config, ctx.throttle, ctx.sessions, ctx.metrics);
+        Ok(handle_complete_login(ctx.stream, ctx.pool, config, throttle, sessions, metrics, ctx.request).await?)
+    })
+}
+
+fn status(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        handle_status(ctx.stream, ctx.pool, ctx.config, &username).await
+    })
+}
+
+fn setup(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        handle_setup(ctx.stream, ctx.pool, ctx.config, &username).await
+    })
+}
+
+fn enable(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        let body = ctx.request.body_str();
+        handle_enable(ctx.stream, ctx.pool, &username, &body).await
+    })
+}
+
+fn disable(ctx: Ctx<'_>) -> BoxFuture<'_, ApiResult> {
+    Box::pin(async move {
+        let username = ctx.username().to_string();
+        let body = ctx.request.body_str();
+        handle_disable(ctx.stream, ctx.pool, ctx.config, ctx.throttle, &username, &body).await
+    })
+}
+
+//----------------------------------------------------------------------------------------------------------
+// two-factor fns
+
+/// Second half of a login: checks a TOTP or recovery code against the
+/// pending challenge and, if it matches, starts the session. Wrong codes
+/// count towards the same backoff and lockout as wrong passwords.
+async fn handle_complete_login(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    config: &Config,
+    throttle: &Throttle,
+    sessions: &SessionStore,
+    metrics: &Metrics,
+    request: &Request,
+) -> anyhow::Result<()> {
+    let form = parse_form_urlencoded(&request.body_str());
+    let code = form.get("code").map(|s| s.as_str()).unwrap_or("");
+    let challenge_hash = sessions.hash(&request.cookie(CHALLENGE_COOKIE).unwrap_or_default());
+
+    let challenge = sqlx::query_as::<_, (i64, String, String, bool)>(
+        "
+        SELECT c.user_id, u.username, u.role, c.remember
+        FROM login_challenges c
+        JOIN users u ON u.id = c.user_id
+        WHERE c.token_hash = ? AND c.expires_at > datetime('now') AND c.attempts < ?
+        ",
+    )
+    .bind(&challenge_hash)
+    .bind(MAX_CHALLENGE_ATTEMPTS)
+    .fetch_optional(&pool)
+    .await?;
+
+    let (user_id, username, role, remember) = match challenge {
+        Some(challenge) => challenge,
+        None => {
+            let html = b"<h1>Sign-in expired, please log in again</h1><a href=\"/\">Back to Login</a>";
+            return send_response(stream, 401, "text/html", html).await;
+        }
+    };
+
+    // codes count towards the same backoff and lockout as passwords, and an
+    // account locked since the password step can't finish signing in
+    let user_key = format!("user:{}", username);
+    if let Err(wait) = throttle.reserve(&[&user_key]) {
+        metrics.login(LoginResult::Throttled);
+        return send_too_many_requests(stream, wait).await;
+    }
+    let login = &config.login;
+    let reserved = db::reserve_login_attempt(&pool, user_id, login.max_failed_attempts, login.lockout_minutes).await?;
+
+    if !reserved || !check_code(&pool, user_id, code).await? {
+        tracing::info!(user_id, locked = !reserved, "Second factor rejected");
+        metrics.login(LoginResult::Failure);
+        sqlx::query("UPDATE login_challenges SET attempts = attempts + 1 WHERE token_hash = ?")
+            .bind(&challenge_hash)
+            .execute(&pool)
+            .await?;
+        let html = b"<h1>Invalid code</h1><a href=\"/2fa.html\">Try again</a>";
+        return send_response(stream, 401, "text/html", html).await;
+    }
+
+    sqlx::query("DELETE FROM login_challenges WHERE token_hash = ?")
+        .bind(&challenge_hash)
+        .execute(&pool)
+        .await?;
+    throttle.clear(&user_key);
+    db::clear_failed_logins(&pool, user_id).await?;
+
+    tracing::info!(user_id, role = %role, "Login successful with second factor");
+    metrics.login(LoginResult::Success);
+
+    let location = match role.as_str() {
+        "admin" => "/admin.html",
+        "lender" => "/lender.html",
+        _ => "/",
+    };
+    let issued = sessions
+        .create(&pool, user_id, &session::Origin::new(stream, request, remember))
+        .await?;
+    let secure = stream.is_secure();
+    let [session_cookie, csrf_cookie] = issued.cookies(secure);
+    Response::new(302)
+        .header("Location", location)
+        .cookie(session_cookie)
+        .cookie(csrf_cookie)
+        .cookie(Cookie::removal(CHALLENGE_COOKIE).secure(secure))
+        .send(stream)
+        .await?;
+    Ok(())
+}
+
+/// Accepts a current TOTP code, or else an unused recovery code, which is
+/// then spent. Each TOTP step is accepted once.
+async fn check_code(pool: &SqlitePool, user_id: i64, code: &str) -> anyhow::Result<bool> {
+    let code = code.trim();
+    if code.len() == 6 && code.bytes().all(|b| b.is_ascii_digit()) {
+        let totp = sqlx::query_as::<_, (String, Option<i64>)>(
+            "SELECT secret, last_used_step FROM user_totp WHERE user_id = ? AND enabled_at IS NOT NULL",
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+        let Some((secret, last_used_step)) = totp else {
+            return Ok(false);
+        };
+        let Some(step) = totp::verify(&secret, code, chrono::Utc::now().timestamp(), last_used_step) else {
+            return Ok(false);
+        };
+
+        // guarded, so two requests racing with the same code can't both win
+        let claimed = sqlx::query(
+            "
+            UPDATE user_totp SET last_used_step = ?
+            WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)
+            ",
+        )
+        .bind(step)
+        .bind(user_id)
+        .bind(step)
+        .execute(pool)
+        .await?;
+        return Ok(claimed.rows_affected() == 1);
+    }
+
+    let code = normalize_recovery_code(code);
+    if code.is_empty() {
+        return Ok(false);
+    }
+    let unused = sqlx::query_as::<_, (i64, String)>(
+        "SELECT id, code_hash FROM recovery_codes WHERE user_id = ? AND used_at IS NULL",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    for (id, code_hash) in unused {
+        if verify_password(&code, &code_hash).await? {
+            let claimed = sqlx::query(
+                "UPDATE recovery_codes SET used_at = datetime('now') WHERE id = ? AND used_at IS NULL",
+            )
+            .bind(id)
+            .execute(pool)
+            .await?;
+            return Ok(claimed.rows_affected() == 1);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether 2FA is on, how many recovery codes are left and whether the
+/// caller's role requires it
+async fn handle_status(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    config: &Config,
+    username: &str,
+) -> ApiResult {
+    let (user_id, role) = user_by_name(&pool, username).await?;
+    let enabled = is_enabled(&pool, user_id).await?;
+    let recovery_codes_left = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM recovery_codes WHERE user_id = ? AND used_at IS NULL",
+    )
+    .bind(user_id)
+    .fetch_one(&pool)
+    .await?;
+
+    let response = serde_json::json!({
+        "enabled": enabled,
+        "required": must_enroll(config, &role, false),
+        "recovery_codes_left": recovery_codes_left
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Generates a new secret for the authenticator app. It stays pending, and
+/// logins keep working without it, until a code from the app confirms it.
+async fn handle_setup(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    config: &Config,
+    username: &str,
+) -> ApiResult {
+    let (user_id, _) = user_by_name(&pool, username).await?;
+    if is_enabled(&pool, user_id).await? {
+        return Err(ApiError::Conflict("two-factor authentication is already enabled".to_string()));
+    }
+
+    let secret = totp::generate_secret();
+    sqlx::query(
+        "
+        INSERT INTO user_totp (user_id, secret) VALUES (?, ?)
+        ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, last_used_step = NULL
+        ",
+    )
+    .bind(user_id)
+    .bind(&secret)
+    .execute(&pool)
+    .await?;
+
+    let response = serde_json::json!({
+        "otpauth_uri": totp::otpauth_uri(&config.two_factor.issuer, username, &secret),
+        "secret": secret
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Confirms the pending secret with a code from the app, switches 2FA on and
+/// returns a fresh set of recovery codes. They are only shown this once.
+async fn handle_enable(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    username: &str,
+    body: &str,
+) -> ApiResult {
+    let input: CodeInput = serde_json::from_str(body)
+        .map_err(|e| ApiError::Validation(format!("invalid 2fa JSON: {}", e)))?;
+    let (user_id, _) = user_by_name(&pool, username).await?;
+
+    let secret = sqlx::query_scalar::<_, String>(
+        "SELECT secret FROM user_totp WHERE user_id = ? AND enabled_at IS NULL",
+    )
+    .bind(user_id)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| ApiError::Conflict("no pending setup, start with /account/api/2fa/setup".to_string()))?;
+
+    let step = totp::verify(&secret, &input.code, chrono::Utc::now().timestamp(), None)
+        .ok_or_else(|| ApiError::Validation("code is incorrect, check the app's clock".to_string()))?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| generate_recovery_code()).collect();
+    let mut hashes = Vec::with_capacity(codes.len());
+    for code in &codes {
+        hashes.push(hash_password(&normalize_recovery_code(code)).await?);
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE user_totp SET enabled_at = datetime('now'), last_used_step = ? WHERE user_id = ?")
+        .bind(step)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    for code_hash in &hashes {
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)")
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": "Two-factor authentication enabled, store the recovery codes somewhere safe",
+        "recovery_codes": codes
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Turns 2FA off after re-checking the password. Not allowed while the
+/// caller's role requires it.
+async fn handle_disable(
+    stream: &mut Conn,
+    pool: SqlitePool,
+    config: &Config,
+    throttle: &Throttle,
+    username: &str,
+    body: &str,
+) -> ApiResult {
+    let input: PasswordInput = serde_json::from_str(body)
+        .map_err(|e| ApiError::Validation(format!("invalid 2fa JSON: {}", e)))?;
+
+    let user_key = format!("user:{}", username);
+    if throttle.reserve(&[&user_key]).is_err() {
+        return Err(ApiError::TooManyRequests("too many attempts, try again later".to_string()));
+    }
+
+    let (user_id, db_password, role) = sqlx::query_as::<_, (i64, String, String)>(
+        "SELECT id, password, role FROM users WHERE username = ?",
+    )
+    .bind(username)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound("user not found".to_string()))?;
+
+    if !verify_password(&input.password, &db_password).await? {
+        return Err(ApiError::Forbidden("password is incorrect".to_string()));
+    }
+    throttle.refund(&user_key);
+    if must_enroll(config, &role, false) {
+        return Err(ApiError::Forbidden("two-factor authentication is required for admins".to_string()));
+    }
+
+    remove(&pool, user_id).await?;
+
+    let response = serde_json::json!({
+        "success": true,
+        "message": "Two-factor authentication disabled"
+    });
+    let json = serde_json::to_vec(&response)?;
+    Ok(send_json(stream, &json).await?)
+}
+
+/// Forgets the user's authenticator and recovery codes
+pub async fn remove(pool: &SqlitePool, user_id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM user_totp WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+async fn user_by_name(pool: &SqlitePool, username: &str) -> Result<(i64, String), ApiError> {
+    sqlx::query_as::<_, (i64, String)>("SELECT id, role FROM users WHERE username = ?")
+        .bind(username)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("user not found".to_string()))
+}
+
+/// "xxxxx-xxxxx", about 49 bits
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code: String = (0..10)
+        .map(|_| RECOVERY_ALPHABET[rng.gen_range(0..RECOVERY_ALPHABET.len())] as char)
+        .collect();
+    code.insert(5, '-');
+    code
+}
+
+/// Codes are compared without dashes, spaces or case
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
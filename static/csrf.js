@@ -0,0 +1,24 @@
+// State-changing requests must echo the session's CSRF token, which the
+// server hands out at login in the script-readable `csrf` cookie. This wraps
+// fetch so every non-GET call on the dashboards sends it along.
+(() => {
+  const plainFetch = window.fetch.bind(window);
+
+  function csrfToken() {
+    const match = document.cookie.match(/(?:^|;\s*)csrf=([^;]*)/);
+    return match ? match[1] : '';
+  }
+
+  window.fetch = (url, options = {}) => {
+    const method = (options.method || 'GET').toUpperCase();
+    if (method !== 'GET' && method !== 'HEAD') {
+      options = { ...options, headers: { ...options.headers, 'X-CSRF-Token': csrfToken() } };
+    }
+    return plainFetch(url, options);
+  };
+})();
+
+async function logout() {
+  await fetch('/logout', { method: 'POST' }).catch(() => {});
+  window.location.href = '/';
+}